@@ -1,259 +1,742 @@
-//! Asynchonous implementation of the TFTP client,
-//! using [`smol-rs`](https://github.com/smol-rs/smol) components
+//! Asynchonous implementation of the TFTP client, generic over the [`Transport`] it runs on
+//!
+//! With the `async` feature, [`async_net::UdpSocket`](https://docs.rs/async-net) from
+//! [`smol-rs`](https://github.com/smol-rs/smol) is a [`Transport`], as is
+//! [`tokio::net::UdpSocket`](https://docs.rs/tokio) with the `tokio` feature.
 
 use std::{
-    ffi::CString,
-    io::ErrorKind,
+    future::{
+        poll_fn,
+        Future,
+    },
+    io::{
+        Cursor,
+        ErrorKind,
+    },
     net::SocketAddr,
-    time::Duration,
+    pin::pin,
+    sync::Arc,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-use async_io::Timer;
-use async_net::UdpSocket;
-use futures_lite::FutureExt;
+#[cfg(feature = "async")]
+use futures_lite::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
 use tracing::debug;
 
 use crate::{
-    parser::{
-        Packet,
-        RequestMode,
+    client::Settings,
+    netascii,
+    parser::RequestMode,
+    retry::{
+        Exponential,
+        RetryPolicy,
+    },
+    transfer::{
+        exponential,
+        start_over,
+        NewPolicy,
+        Observer,
+        ReadTransfer,
+        Transfer,
+        WriteTransfer,
     },
+    ClientBuilder,
     Error,
-    State,
-    BLKSIZE,
+    Options,
 };
 
+/// An async datagram socket plus a timer, which is everything the client needs from a runtime
+pub trait Transport {
+    /// Send a datagram to `target`, returning the number of bytes sent
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<usize>>;
+    /// Receive a datagram into `buf`, returning the number of bytes received and where it came
+    /// from
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>>;
+    /// Wait for `duration` to pass
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+#[cfg(feature = "async")]
+impl Transport for async_net::UdpSocket {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<usize>> {
+        async_net::UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>> {
+        async_net::UdpSocket::recv_from(self, buf)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        async_io::Timer::after(duration).await;
+    }
+}
+
+/// Receive a datagram, giving up with [`ErrorKind::TimedOut`] if nothing arrives in time
+async fn recv_timeout<S: Transport>(
+    socket: &S,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> std::io::Result<(usize, SocketAddr)> {
+    let mut recv = pin!(socket.recv_from(buf));
+    let mut sleep = pin!(socket.sleep(timeout));
+    poll_fn(|cx| {
+        if let Poll::Ready(res) = recv.as_mut().poll(cx) {
+            return Poll::Ready(res);
+        }
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(ErrorKind::TimedOut.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// An async TFTP client over any [`Transport`], which applies the same configuration to every
+/// transfer
+///
+/// Build one with [`ClientBuilder::build_async`] (with the `async` feature),
+/// [`ClientBuilder::build_tokio`] (with the `tokio` feature), or [`ClientBuilder::build_with`]
+/// for a transport of your own.
+#[derive(Debug)]
+pub struct Client<S> {
+    pub(crate) socket: S,
+    pub(crate) settings: Settings,
+}
+
+impl ClientBuilder {
+    /// Check the configuration, and build an async client that runs over `socket` (ignoring the
+    /// bind address)
+    pub fn build_with<S: Transport>(self, socket: S) -> Result<Client<S>, Error> {
+        Ok(Client {
+            socket,
+            settings: self.validate()?,
+        })
+    }
+
+    /// Check the configuration, and bind the socket for an async client
+    #[cfg(feature = "async")]
+    pub async fn build_async(self) -> Result<Client<async_net::UdpSocket>, Error> {
+        let socket = async_net::UdpSocket::bind(self.bind_addr())
+            .await
+            .map_err(Error::SocketIo)?;
+        self.build_with(socket)
+    }
+}
+
+impl<S: Transport> Client<S> {
+    /// The socket the client sends from
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    /// Download a file
+    pub async fn get<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        filename: T,
+    ) -> Result<Vec<u8>, Error> {
+        let s = &self.settings;
+        let mut file_data = vec![];
+        download_into(
+            filename,
+            &mut file_data,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await?;
+        Ok(file_data)
+    }
+
+    /// Download a file, writing each block to `writer` as soon as it arrives
+    ///
+    /// Returns the number of bytes written. With the `tokio` feature, `get_to_tokio` does the same
+    /// through tokio's `AsyncWrite`.
+    #[cfg(feature = "async")]
+    pub async fn get_to<T: AsRef<str> + std::fmt::Display, W: AsyncWrite + Unpin>(
+        &self,
+        filename: T,
+        writer: W,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        download_into(
+            filename,
+            &mut WriteSink(writer),
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await
+    }
+
+    /// Upload a file
+    pub async fn put<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        filename: T,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let s = &self.settings;
+        upload_slice(
+            filename,
+            data,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await
+    }
+
+    /// Upload a file, reading the data from `reader` one block at a time until it runs out
+    ///
+    /// Returns the number of bytes read. With the `tokio` feature, `put_from_tokio` does the same
+    /// through tokio's `AsyncRead`.
+    #[cfg(feature = "async")]
+    pub async fn put_from<T: AsRef<str> + std::fmt::Display, R: AsyncRead + Unpin>(
+        &self,
+        filename: T,
+        reader: R,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        upload_inner(
+            filename,
+            ReadSource(reader),
+            None,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await
+    }
+
+    /// Query the size of a file without downloading it
+    pub async fn size<T: AsRef<str> + std::fmt::Display>(&self, filename: T) -> Result<u64, Error> {
+        let s = &self.settings;
+        query_size_inner(filename, &self.socket, s.server, s.policy(), &s.options).await
+    }
+}
+
 /// Download a file via tftp
-pub async fn download<T: AsRef<str> + std::fmt::Display>(
+pub async fn download<T: AsRef<str> + std::fmt::Display, S: Transport>(
     filename: T,
-    socket: &UdpSocket,
-    mut server: SocketAddr,
+    socket: &S,
+    server: SocketAddr,
     timeout: Duration,
     max_timeout: Duration,
     retries: usize,
 ) -> Result<Vec<u8>, Error> {
-    // Set our server address to the inital address, it will potentially change
-    debug!("┌── GET {filename}");
-    // Initialize the state of our state machine
-    let mut state = State::Send;
-    let mut local_retries = retries;
-    let mut local_timeout = timeout;
-    let mut send_pkt = Packet::ReadRequest {
-        filename: CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?,
-        mode: RequestMode::Octet,
-    };
+    download_with_options(
+        filename,
+        socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+    .await
+}
+
+/// Download a file via tftp, negotiating `options` with the server
+#[allow(clippy::too_many_arguments)]
+pub async fn download_with_options<T: AsRef<str> + std::fmt::Display, S: Transport>(
+    filename: T,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<Vec<u8>, Error> {
     let mut file_data = vec![];
-    let mut done = false;
-    // Run the state machine
+    download_into(
+        filename,
+        &mut file_data,
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+    .await?;
+    Ok(file_data)
+}
+
+/// Download a file via tftp, negotiating `options` with the server and writing each block to
+/// `writer` as soon as it arrives
+///
+/// Returns the number of bytes written
+#[cfg(feature = "async")]
+#[allow(clippy::too_many_arguments)]
+pub async fn download_to<T: AsRef<str> + std::fmt::Display, S: Transport, W: AsyncWrite + Unpin>(
+    filename: T,
+    writer: W,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    download_into(
+        filename,
+        &mut WriteSink(writer),
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+    .await
+}
+
+/// Somewhere to put downloaded data, which lets us pre-allocate (and start over) when collecting
+/// into a `Vec`
+pub(crate) trait Sink {
+    fn reserve(&mut self, _size: u64) {}
+    async fn put(&mut self, data: &[u8]) -> std::io::Result<()>;
+    /// Throw away everything put so far, if we can
+    fn reset(&mut self) -> bool {
+        false
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn reserve(&mut self, size: u64) {
+        // Best effort, the server could be lying to us
+        let _ = self.try_reserve(size as usize);
+    }
+
+    fn reset(&mut self) -> bool {
+        self.clear();
+        true
+    }
+
+    async fn put(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+struct WriteSink<W>(W);
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + Unpin> Sink for WriteSink<W> {
+    async fn put(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(data).await
+    }
+}
+
+pub(crate) async fn download_into<T: AsRef<str> + std::fmt::Display, S: Transport, K: Sink>(
+    filename: T,
+    sink: &mut K,
+    socket: &S,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<u64, Error> {
+    // The deadline covers the whole transfer, however many times it starts over
+    let started = Instant::now();
+    let mut attempts = 0;
     loop {
-        match state {
-            State::Send => {
-                local_retries = retries;
-                local_timeout = timeout;
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt}");
-                // Send the bytes and reset some other state variables
-                socket
-                    .send_to(&bytes, server)
-                    .await
-                    .map_err(Error::SocketIo)?;
-                // Transition to recv if this wasn't the last ACK packet
-                if done {
-                    break;
-                }
-                state = State::Recv
+        let mut written = 0;
+        let policy = new_policy();
+        match download_attempt(
+            &filename,
+            sink,
+            &mut written,
+            socket,
+            server,
+            policy,
+            observer,
+            started,
+            options,
+        )
+        .await
+        {
+            Err(e)
+                if start_over(options, started, attempts, &e) && (written == 0 || sink.reset()) =>
+            {
+                attempts += 1;
+                debug!(
+                    "└ Starting over ({attempts}/{}) - {e}",
+                    options.transfer_retries
+                );
             }
-            State::SendAgain => {
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt} (Retry)");
-                // Send the bytes and reset some other state variables
-                socket
-                    .send_to(&bytes, server)
-                    .await
-                    .map_err(Error::SocketIo)?;
-                // Transition to recv
-                state = State::Recv
-            }
-            State::Recv => {
-                let mut buf = vec![0; BLKSIZE + 4]; // The biggest a block can be, 2 bytes for opcode, 2 bytes for block n
-                let n = match socket
-                    .recv_from(&mut buf)
-                    .or(async {
-                        Timer::after(local_timeout).await;
-                        Err(ErrorKind::TimedOut.into())
-                    })
-                    .await
-                {
-                    Ok((n, remote_addr)) => {
-                        // Update the server's address as the spec allows the port to change
-                        server = remote_addr;
-                        n
-                    }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {
-                        debug!("│ Timeout");
-                        // Timeout, try sending the last packet again with exponential backoff
-                        local_retries -= 1;
-                        if local_retries == 0 {
-                            return Err(Error::Timeout);
-                        }
-                        local_timeout += local_timeout / 2;
-                        if local_timeout > max_timeout {
-                            local_timeout = max_timeout;
-                        }
-                        state = State::SendAgain;
-                        continue;
-                    }
-                    Err(e) => return Err(Error::SocketIo(e)),
-                };
-                // Process the received packet
-                let recv_pkt = Packet::from_bytes(&buf[..n]).map_err(Error::Parse)?;
-                debug!("│ RX - {recv_pkt}");
-                match recv_pkt {
-                    Packet::Data { block_n, data } => {
-                        // We got back a chunk of data, we need to ack it and append to the data
-                        // we're collecting
-                        file_data.extend_from_slice(&data);
-                        if data.len() < BLKSIZE {
-                            done = true
-                        }
-                        send_pkt = Packet::Acknowledgment { block_n };
-                        state = State::Send;
-                        continue;
-                    }
-                    Packet::Error { code, msg } => {
-                        return Err(Error::Protocol {
-                            code,
-                            msg: msg.into_string().expect("Error message had invalid UTF-8"),
-                        })
-                    }
-                    _ => return Err(Error::UnexpectedPacket(recv_pkt)),
-                }
+            res => return res,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_attempt<T: AsRef<str> + std::fmt::Display, S: Transport, K: Sink>(
+    filename: T,
+    sink: &mut K,
+    written: &mut u64,
+    socket: &S,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    observer: Option<&Arc<dyn Observer>>,
+    started: Instant,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut transfer = ReadTransfer::with_policy(filename, server, policy, options)?;
+    transfer.started_at(started);
+    if let Some(observer) = observer {
+        transfer.observe(observer.clone());
+    }
+    let mut first = true;
+    loop {
+        drive(socket, &mut transfer, |transfer| transfer.has_data()).await?;
+        let Some(block) = transfer.poll_data() else {
+            break;
+        };
+        if first {
+            if let Some(tsize) = transfer.transfer_size() {
+                sink.reserve(tsize);
             }
+            first = false;
+        }
+        if let Err(e) = sink.put(&block).await {
+            return Err(abort(socket, &mut transfer, Error::Write(e)).await);
+        }
+        *written += block.len() as u64;
+    }
+    // And return how much we downloaded
+    Ok(*written)
+}
+
+/// Drive `transfer` over `socket` until `ready` says we can stop (or the server has nothing more
+/// to say), aborting it if anything goes wrong
+async fn drive<S: Transport, X: Transfer>(
+    socket: &S,
+    transfer: &mut X,
+    ready: impl Fn(&X) -> bool,
+) -> Result<(), Error> {
+    match run(socket, transfer, ready).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(abort(socket, transfer, e).await),
+    }
+}
+
+/// Give up on `transfer` because of `error`, letting the server know (if we can)
+async fn abort<S: Transport, X: Transfer>(socket: &S, transfer: &mut X, error: Error) -> Error {
+    debug!("│ Aborting - {error}");
+    transfer.abort(&error);
+    while let Some(transmit) = transfer.poll_transmit(Instant::now()) {
+        // We're already failing, so there's nothing to be done if this doesn't get through
+        let _ = socket
+            .send_to(&transmit.datagram, transmit.destination)
+            .await;
+    }
+    error
+}
+
+async fn run<S: Transport, X: Transfer>(
+    socket: &S,
+    transfer: &mut X,
+    ready: impl Fn(&X) -> bool,
+) -> Result<(), Error> {
+    let mut buf = vec![];
+    loop {
+        while let Some(transmit) = transfer.poll_transmit(Instant::now()) {
+            socket
+                .send_to(&transmit.datagram, transmit.destination)
+                .await
+                .map_err(Error::SocketIo)?;
+        }
+        let Some(timeout) = transfer.timeout(Instant::now()) else {
+            return Ok(());
+        };
+        if ready(transfer) {
+            return Ok(());
+        }
+        buf.resize(transfer.max_datagram_len(), 0);
+        match recv_timeout(socket, &mut buf, timeout).await {
+            Ok((n, source)) => transfer.on_datagram(Instant::now(), source, &buf[..n])?,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => transfer.on_timeout(Instant::now())?,
+            Err(e) => return Err(Error::SocketIo(e)),
         }
     }
-    debug!("└");
-    Ok(file_data)
 }
 
 /// Upload a file via tftp
-pub async fn upload<T: AsRef<str> + std::fmt::Display>(
+pub async fn upload<T: AsRef<str> + std::fmt::Display, S: Transport>(
+    filename: T,
+    data: &[u8],
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+) -> Result<(), Error> {
+    upload_with_options(
+        filename,
+        data,
+        socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+    .await
+}
+
+/// Upload a file via tftp, negotiating `options` with the server
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_with_options<T: AsRef<str> + std::fmt::Display, S: Transport>(
     filename: T,
     data: &[u8],
-    socket: &UdpSocket,
-    mut server: SocketAddr,
+    socket: &S,
+    server: SocketAddr,
     timeout: Duration,
     max_timeout: Duration,
     retries: usize,
+    options: &Options,
 ) -> Result<(), Error> {
-    debug!("┌── PUT {filename}");
-    // Initialize the state of our state machine
-    let mut state = State::Send;
-    let mut local_retries = retries;
-    let mut local_timeout = timeout;
-    let mut send_pkt = Packet::WriteRequest {
-        filename: CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?,
-        mode: RequestMode::Octet,
+    let new_policy = exponential(timeout, max_timeout, retries);
+    upload_slice(filename, data, socket, server, &new_policy, None, options).await
+}
+
+async fn upload_slice<T: AsRef<str> + std::fmt::Display, S: Transport>(
+    filename: T,
+    data: &[u8],
+    socket: &S,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<(), Error> {
+    // We know exactly how much we'll be sending up front
+    let size = match options.mode {
+        RequestMode::Octet => data.len(),
+        _ => netascii::encoded_len(data),
     };
-    // Create the chunk vec for our data
-    let chunks: Vec<_> = data.chunks(BLKSIZE).collect();
-    let mut last_block_n = -1;
-    // Run the state machine
+    upload_inner(
+        filename,
+        Cursor::new(data),
+        Some(size as u64),
+        socket,
+        server,
+        new_policy,
+        observer,
+        options,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Upload a file via tftp, negotiating `options` with the server and reading the data from
+/// `reader` one block at a time until it runs out
+///
+/// As the size isn't known up front, the `tsize` option isn't sent. Returns the number of bytes
+/// read.
+#[cfg(feature = "async")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_from<T: AsRef<str> + std::fmt::Display, S: Transport, R: AsyncRead + Unpin>(
+    filename: T,
+    reader: R,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    upload_inner(
+        filename,
+        ReadSource(reader),
+        None,
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+    .await
+}
+
+/// Where uploaded data comes from, which lets us start over when uploading a slice
+pub(crate) trait Source {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+    /// Go back to the start of the data, if we can
+    fn rewind(&mut self) -> bool {
+        false
+    }
+}
+
+impl Source for Cursor<&[u8]> {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::io::Read::read(self, buf)
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.set_position(0);
+        true
+    }
+}
+
+#[cfg(feature = "async")]
+struct ReadSource<R>(R);
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> Source for ReadSource<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn upload_inner<T: AsRef<str> + std::fmt::Display, S: Transport, R: Source>(
+    filename: T,
+    mut source: R,
+    size: Option<u64>,
+    socket: &S,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<u64, Error> {
+    // The deadline covers the whole transfer, however many times it starts over
+    let started = Instant::now();
+    let mut attempts = 0;
     loop {
-        match state {
-            State::Send => {
-                local_retries = retries;
-                local_timeout = timeout;
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt}");
-                // Send the bytes and reset some other state variables
-                socket
-                    .send_to(&bytes, server)
-                    .await
-                    .map_err(Error::SocketIo)?;
-                // Transition to recv if this wasn't the last ACK packet
-                state = State::Recv;
-            }
-            State::SendAgain => {
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt} (Retry)");
-                // Send the bytes and reset some other state variables
-                socket
-                    .send_to(&bytes, server)
-                    .await
-                    .map_err(Error::SocketIo)?;
-                // Transition to recv
-                state = State::Recv
-            }
-            State::Recv => {
-                let mut buf = vec![0; BLKSIZE + 4];
-
-                let n = match socket
-                    .recv_from(&mut buf)
-                    .or(async {
-                        Timer::after(local_timeout).await;
-                        Err(ErrorKind::TimedOut.into())
-                    })
-                    .await
-                {
-                    Ok((n, remote_addr)) => {
-                        // Update the server's address as the spec allows the port to change
-                        server = remote_addr;
-                        n
-                    }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {
-                        debug!("│ Timeout");
-                        // Timeout, try sending the last packet again with exponential backoff
-                        local_retries -= 1;
-                        if local_retries == 0 {
-                            return Err(Error::Timeout);
-                        }
-                        local_timeout += local_timeout / 2;
-                        if local_timeout > max_timeout {
-                            local_timeout = max_timeout;
-                        }
-                        state = State::SendAgain;
-                        continue;
-                    }
-                    Err(e) => return Err(Error::SocketIo(e)),
-                };
-                // Process the received packet
-                let recv_pkt = Packet::from_bytes(&buf[..n]).map_err(Error::Parse)?;
-                debug!("│ RX - {recv_pkt}");
-                match recv_pkt {
-                    Packet::Acknowledgment { block_n } => {
-                        // Fix for https://en.wikipedia.org/wiki/Sorcerer%27s_Apprentice_Syndrome
-                        // Just try to recv again and don't resend the data on duplicate Acks
-                        if last_block_n == -1 {
-                            // Initial block
-                            last_block_n = block_n as i16
-                        } else if last_block_n == block_n as i16 {
-                            state = State::Recv;
-                            continue;
-                        } else {
-                            last_block_n = block_n as i16;
-                        }
-                        // We got back an ack, we need to send out that ack's chunk of data
-                        if block_n as usize == chunks.len() {
-                            break;
-                        }
-                        send_pkt = Packet::Data {
-                            block_n: block_n + 1,
-                            data: chunks[block_n as usize].into(),
-                        };
-                        state = State::Send;
-                        continue;
-                    }
-                    Packet::Error { code, msg } => {
-                        return Err(Error::Protocol {
-                            code,
-                            msg: msg.into_string().expect("Error message had invalid UTF-8"),
-                        })
-                    }
-                    _ => return Err(Error::UnexpectedPacket(recv_pkt)),
-                }
+        let mut read = 0;
+        let policy = new_policy();
+        match upload_attempt(
+            &filename,
+            &mut source,
+            &mut read,
+            size,
+            socket,
+            server,
+            policy,
+            observer,
+            started,
+            options,
+        )
+        .await
+        {
+            Err(e)
+                if start_over(options, started, attempts, &e) && (read == 0 || source.rewind()) =>
+            {
+                attempts += 1;
+                debug!(
+                    "└ Starting over ({attempts}/{}) - {e}",
+                    options.transfer_retries
+                );
             }
+            res => return res,
         }
     }
-    debug!("└");
-    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_attempt<T: AsRef<str> + std::fmt::Display, S: Transport, R: Source>(
+    filename: T,
+    reader: &mut R,
+    read: &mut u64,
+    size: Option<u64>,
+    socket: &S,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    observer: Option<&Arc<dyn Observer>>,
+    started: Instant,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut transfer = WriteTransfer::with_policy(filename, size, server, policy, options)?;
+    transfer.started_at(started);
+    if let Some(observer) = observer {
+        transfer.observe(observer.clone());
+    }
+    let ready = |transfer: &WriteTransfer| transfer.is_done() || transfer.wants_data();
+    // Wait for the server to ack the request before we start sending data
+    drive(socket, &mut transfer, ready).await?;
+    let mut buf = vec![0; transfer.blksize()];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(abort(socket, &mut transfer, Error::Read(e)).await),
+        };
+        *read += n as u64;
+        transfer.write(&buf[..n]);
+        // Once the window is full, we have to hear back from the server before taking more
+        drive(socket, &mut transfer, ready).await?;
+    }
+    transfer.finish();
+    drive(socket, &mut transfer, ready).await?;
+    // And return how much we uploaded
+    Ok(transfer.written())
+}
+
+/// Query the size of a file via tftp without downloading it
+///
+/// This sends a RRQ with the `tsize` option as per
+/// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349) and aborts the transfer as soon as
+/// the server acknowledges it
+pub async fn query_size<T: AsRef<str> + std::fmt::Display, S: Transport>(
+    filename: T,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+) -> Result<u64, Error> {
+    let policy = Exponential::new(timeout, max_timeout, retries);
+    query_size_inner(
+        filename,
+        socket,
+        server,
+        Box::new(policy),
+        &Options::default(),
+    )
+    .await
+}
+
+async fn query_size_inner<T: AsRef<str> + std::fmt::Display, S: Transport>(
+    filename: T,
+    socket: &S,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut transfer = ReadTransfer::query_size(filename, server, policy, options)?;
+    drive(socket, &mut transfer, |_| false).await?;
+    transfer
+        .transfer_size()
+        .ok_or_else(|| Error::UnsupportedOption("tsize".to_owned()))
 }
@@ -1,141 +1,422 @@
 //! Blocking implementation of the TFTP client
 
 use std::{
-    ffi::CString,
+    io::{
+        Cursor,
+        Read,
+        Write,
+    },
     net::{
         SocketAddr,
         UdpSocket,
     },
-    time::Duration,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use tracing::debug;
 
 use crate::{
-    parser::{
-        Packet,
-        RequestMode,
+    netascii,
+    parser::RequestMode,
+    retry::{
+        Exponential,
+        RetryPolicy,
+    },
+    transfer::{
+        exponential,
+        start_over,
+        NewPolicy,
+        Observer,
+        ReadTransfer,
+        Transfer,
+        WriteTransfer,
     },
     Error,
-    State,
-    BLKSIZE,
+    Options,
 };
 
 /// Download a file via tftp
 pub fn download<T: AsRef<str> + std::fmt::Display>(
     filename: T,
     socket: &UdpSocket,
-    mut server: SocketAddr,
+    server: SocketAddr,
     timeout: Duration,
     max_timeout: Duration,
     retries: usize,
 ) -> Result<Vec<u8>, Error> {
-    // Set our server address to the inital address, it will potentially change
-    // Make sure we can actually timeout, but preserve the old state
-    let old_read_timeout = socket.read_timeout().map_err(Error::SocketIo)?;
-    socket
-        .set_read_timeout(Some(timeout))
-        .map_err(Error::SocketIo)?;
-    debug!("┌── GET {filename}");
-    // Initialize the state of our state machine
-    let mut state = State::Send;
-    let mut local_retries = retries;
-    let mut local_timeout = timeout;
-    let mut send_pkt = Packet::ReadRequest {
-        filename: CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?,
-        mode: RequestMode::Octet,
-    };
+    download_with_options(
+        filename,
+        socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+}
+
+/// Download a file via tftp, negotiating `options` with the server
+#[allow(clippy::too_many_arguments)]
+pub fn download_with_options<T: AsRef<str> + std::fmt::Display>(
+    filename: T,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<Vec<u8>, Error> {
     let mut file_data = vec![];
-    let mut done = false;
-    // Run the state machine
+    download_into(
+        filename,
+        &mut file_data,
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )?;
+    Ok(file_data)
+}
+
+/// Download a file via tftp, negotiating `options` with the server and writing each block to
+/// `writer` as soon as it arrives
+///
+/// Returns the number of bytes written
+#[allow(clippy::too_many_arguments)]
+pub fn download_to<T: AsRef<str> + std::fmt::Display, W: Write>(
+    filename: T,
+    writer: W,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    download_into(
+        filename,
+        &mut WriteSink(writer),
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+}
+
+/// Somewhere to put downloaded data, which lets us pre-allocate (and start over) when collecting
+/// into a `Vec`
+pub(crate) trait Sink {
+    fn reserve(&mut self, _size: u64) {}
+    fn put(&mut self, data: &[u8]) -> std::io::Result<()>;
+    /// Throw away everything put so far, if we can
+    fn reset(&mut self) -> bool {
+        false
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn reserve(&mut self, size: u64) {
+        // Best effort, the server could be lying to us
+        let _ = self.try_reserve(size as usize);
+    }
+
+    fn reset(&mut self) -> bool {
+        self.clear();
+        true
+    }
+
+    fn put(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+pub(crate) struct WriteSink<W>(pub(crate) W);
+
+impl<W: Write> Sink for WriteSink<W> {
+    fn put(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(data)
+    }
+}
+
+pub(crate) fn download_into<T: AsRef<str> + std::fmt::Display, S: Sink>(
+    filename: T,
+    sink: &mut S,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<u64, Error> {
+    // The deadline covers the whole transfer, however many times it starts over
+    let started = Instant::now();
+    let mut attempts = 0;
     loop {
-        match state {
-            State::Send => {
-                local_retries = retries;
-                local_timeout = timeout;
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt}");
-                // Send the bytes and reset some other state variables
-                socket.send_to(&bytes, server).map_err(Error::SocketIo)?;
-                // Transition to recv if this wasn't the last ACK packet
-                if done {
-                    break;
-                }
-                state = State::Recv
+        let mut written = 0;
+        let policy = new_policy();
+        match download_attempt(
+            &filename,
+            sink,
+            &mut written,
+            socket,
+            server,
+            policy,
+            observer,
+            started,
+            options,
+        ) {
+            Err(e)
+                if start_over(options, started, attempts, &e) && (written == 0 || sink.reset()) =>
+            {
+                attempts += 1;
+                debug!(
+                    "└ Starting over ({attempts}/{}) - {e}",
+                    options.transfer_retries
+                );
             }
-            State::SendAgain => {
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt} (Retry)");
-                // Send the bytes and reset some other state variables
-                socket.send_to(&bytes, server).map_err(Error::SocketIo)?;
-                // Transition to recv
-                state = State::Recv
+            res => return res,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn download_attempt<T: AsRef<str> + std::fmt::Display, S: Sink>(
+    filename: T,
+    sink: &mut S,
+    written: &mut u64,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    observer: Option<&Arc<dyn Observer>>,
+    started: Instant,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut reader = TftpReader::with_policy(filename, socket, server, policy, options)?;
+    reader.transfer.started_at(started);
+    if let Some(observer) = observer {
+        reader.observe(observer.clone());
+    }
+    let mut first = true;
+    while let Some(block) = reader.next_block()? {
+        if first {
+            if let Some(tsize) = reader.transfer_size() {
+                sink.reserve(tsize);
             }
-            State::Recv => {
-                let mut buf = vec![0; BLKSIZE + 4]; // The biggest a block can be, 2 bytes for opcode, 2 bytes for block n
-                let n = match socket.recv_from(&mut buf) {
-                    Ok((n, remote_addr)) => {
-                        // Update the server's address as the spec allows the port to change
-                        server = remote_addr;
-                        n
-                    }
-                    Err(e) => {
-                        match e.kind() {
-                            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
-                                debug!("│ Timeout");
-                                // We timed out, try sending the last packet again with exponential
-                                // backoff
-                                local_retries -= 1;
-                                if local_retries == 0 {
-                                    return Err(Error::Timeout);
-                                }
-                                local_timeout += local_timeout / 2;
-                                if local_timeout > max_timeout {
-                                    local_timeout = max_timeout;
-                                }
-                                socket
-                                    .set_read_timeout(Some(local_timeout))
-                                    .map_err(Error::SocketIo)?;
-                                state = State::SendAgain;
-                                continue;
-                            }
-                            _ => return Err(Error::SocketIo(e)),
-                        }
-                    }
-                };
-                // Process the received packet
-                let recv_pkt = Packet::from_bytes(&buf[..n]).map_err(Error::Parse)?;
-                debug!("│ RX - {recv_pkt}");
-                match recv_pkt {
-                    Packet::Data { block_n, data } => {
-                        // We got back a chunk of data, we need to ack it and append to the data
-                        // we're collecting
-                        file_data.extend_from_slice(&data);
-                        if data.len() < BLKSIZE {
-                            done = true
-                        }
-                        send_pkt = Packet::Acknowledgment { block_n };
-                        state = State::Send;
-                        continue;
-                    }
-                    Packet::Error { code, msg } => {
-                        return Err(Error::Protocol {
-                            code,
-                            msg: msg.into_string().expect("Error message had invalid UTF-8"),
-                        })
-                    }
-                    _ => return Err(Error::UnexpectedPacket(recv_pkt)),
+            first = false;
+        }
+        if let Err(e) = sink.put(&block) {
+            return Err(reader.abort(Error::Write(e)));
+        }
+        *written += block.len() as u64;
+    }
+    // And return how much we downloaded
+    Ok(*written)
+}
+
+/// Drive `transfer` over `socket` until `ready` says we can stop (or the server has nothing more
+/// to say), aborting it if anything goes wrong
+fn drive<X: Transfer>(
+    socket: &UdpSocket,
+    transfer: &mut X,
+    ready: impl Fn(&X) -> bool,
+) -> Result<(), Error> {
+    run(socket, transfer, ready).map_err(|e| abort(socket, transfer, e))
+}
+
+/// Give up on `transfer` because of `error`, letting the server know (if we can)
+fn abort<X: Transfer>(socket: &UdpSocket, transfer: &mut X, error: Error) -> Error {
+    debug!("│ Aborting - {error}");
+    transfer.abort(&error);
+    while let Some(transmit) = transfer.poll_transmit(Instant::now()) {
+        // We're already failing, so there's nothing to be done if this doesn't get through
+        let _ = socket.send_to(&transmit.datagram, transmit.destination);
+    }
+    error
+}
+
+fn run<X: Transfer>(
+    socket: &UdpSocket,
+    transfer: &mut X,
+    ready: impl Fn(&X) -> bool,
+) -> Result<(), Error> {
+    let mut buf = vec![];
+    loop {
+        while let Some(transmit) = transfer.poll_transmit(Instant::now()) {
+            socket
+                .send_to(&transmit.datagram, transmit.destination)
+                .map_err(Error::SocketIo)?;
+        }
+        let Some(timeout) = transfer.timeout(Instant::now()) else {
+            return Ok(());
+        };
+        if ready(transfer) {
+            return Ok(());
+        }
+        if timeout.is_zero() {
+            // The socket can't wait for no time at all (that's an error rather than a timeout),
+            // which happens if the policy says so or we're right up against the deadline
+            transfer.on_timeout(Instant::now())?;
+            continue;
+        }
+        if socket.read_timeout().map_err(Error::SocketIo)? != Some(timeout) {
+            socket
+                .set_read_timeout(Some(timeout))
+                .map_err(Error::SocketIo)?;
+        }
+        buf.resize(transfer.max_datagram_len(), 0);
+        match socket.recv_from(&mut buf) {
+            Ok((n, source)) => transfer.on_datagram(Instant::now(), source, &buf[..n])?,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+                    transfer.on_timeout(Instant::now())?
                 }
+                _ => return Err(Error::SocketIo(e)),
+            },
+        }
+    }
+}
+
+/// A download via tftp that implements [`Read`]
+///
+/// The RRQ is only sent on the first read, after which every block is acknowledged as soon as it
+/// arrives and handed out to subsequent reads. Dropping the reader before the end of the file
+/// abandons the download with an ERROR to the server.
+pub struct TftpReader<'a> {
+    socket: &'a UdpSocket,
+    transfer: ReadTransfer,
+    // The socket timeout to put back once we're done, if we've changed it
+    old_read_timeout: Option<Option<Duration>>,
+    // The most recent block and how much of it has been read
+    block: Vec<u8>,
+    pos: usize,
+    // Once the transfer fails, every read after gets the same error
+    failed: Option<Error>,
+}
+
+impl<'a> TftpReader<'a> {
+    /// Prepare to download a file via tftp, negotiating `options` with the server
+    pub fn new<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        timeout: Duration,
+        max_timeout: Duration,
+        retries: usize,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let policy = Exponential::new(timeout, max_timeout, retries);
+        Self::with_policy(filename, socket, server, Box::new(policy), options)
+    }
+
+    /// Prepare to download a file via tftp, negotiating `options` with the server and retrying
+    /// as `policy` sees fit
+    pub fn with_policy<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            socket,
+            transfer: ReadTransfer::with_policy(filename, server, policy, options)?,
+            old_read_timeout: None,
+            block: vec![],
+            pos: 0,
+            failed: None,
+        })
+    }
+
+    /// The size of the file, if the server told us as per RFC 2349
+    pub fn transfer_size(&self) -> Option<u64> {
+        self.transfer.transfer_size()
+    }
+
+    /// Tell `observer` about everything that happens from here on
+    pub(crate) fn observe(&mut self, observer: Arc<dyn Observer>) {
+        self.transfer.observe(observer);
+    }
+
+    /// Wait for the next block, returning `None` at the end of the file
+    fn next_block(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(error) = &self.failed {
+            return Err(error.again());
+        }
+        if self.old_read_timeout.is_none() {
+            // Make sure we can actually timeout, but preserve the old state
+            self.old_read_timeout = Some(self.socket.read_timeout().map_err(Error::SocketIo)?);
+        }
+        let res = drive(self.socket, &mut self.transfer, |transfer| {
+            transfer.has_data()
+        });
+        if let Err(error) = res {
+            let _ = self.restore();
+            self.failed = Some(error.again());
+            return Err(error);
+        }
+        if self.transfer.timeout(Instant::now()).is_none() {
+            self.restore()?;
+        }
+        Ok(self.transfer.poll_data())
+    }
+
+    /// Give up on the transfer because of `error`, letting the server know
+    fn abort(&mut self, error: Error) -> Error {
+        let error = abort(self.socket, &mut self.transfer, error);
+        let _ = self.restore();
+        self.failed = Some(error.again());
+        error
+    }
+
+    /// Put the socket back the way we found it
+    fn restore(&mut self) -> Result<(), Error> {
+        if let Some(old_read_timeout) = self.old_read_timeout.take() {
+            // Return socket timeout to previous state
+            self.socket
+                .set_read_timeout(old_read_timeout)
+                .map_err(Error::SocketIo)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TftpReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Fetch blocks until there's something to hand out, or we run out
+        while self.pos == self.block.len() {
+            match self.next_block()? {
+                Some(block) => {
+                    self.block = block;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
             }
         }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for TftpReader<'_> {
+    fn drop(&mut self) {
+        // If the transfer was cut short, let the server know rather than leaving it to
+        // retransmit until it times out
+        if self.failed.is_none() && !self.transfer.is_done() {
+            let error =
+                std::io::Error::other("The download was dropped before the end of the file");
+            self.abort(Error::Write(error));
+        }
+        // Put the socket back the way we found it
+        let _ = self.restore();
     }
-    debug!("└");
-    // Return socket timeout to previous state
-    socket
-        .set_read_timeout(old_read_timeout)
-        .map_err(Error::SocketIo)?;
-    // And return the bytes we downloaded
-    Ok(file_data)
 }
 
 /// Upload a file via tftp
@@ -143,125 +424,394 @@ pub fn upload<T: AsRef<str> + std::fmt::Display>(
     filename: T,
     data: &[u8],
     socket: &UdpSocket,
-    mut server: SocketAddr,
+    server: SocketAddr,
     timeout: Duration,
     max_timeout: Duration,
     retries: usize,
 ) -> Result<(), Error> {
-    // Make sure we can actually timeout, but preserve the old state
-    let old_read_timeout = socket.read_timeout().map_err(Error::SocketIo)?;
-    socket
-        .set_read_timeout(Some(timeout))
-        .map_err(Error::SocketIo)?;
-    debug!("┌── PUT {filename}");
-    // Initialize the state of our state machine
-    let mut state = State::Send;
-    let mut local_retries = retries;
-    let mut local_timeout = timeout;
-    let mut send_pkt = Packet::WriteRequest {
-        filename: CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?,
-        mode: RequestMode::Octet,
+    upload_with_options(
+        filename,
+        data,
+        socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+}
+
+/// Upload a file via tftp, negotiating `options` with the server
+#[allow(clippy::too_many_arguments)]
+pub fn upload_with_options<T: AsRef<str> + std::fmt::Display>(
+    filename: T,
+    data: &[u8],
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<(), Error> {
+    let new_policy = exponential(timeout, max_timeout, retries);
+    upload_slice(filename, data, socket, server, &new_policy, None, options)
+}
+
+pub(crate) fn upload_slice<T: AsRef<str> + std::fmt::Display>(
+    filename: T,
+    data: &[u8],
+    socket: &UdpSocket,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<(), Error> {
+    // We know exactly how much we'll be sending up front
+    let size = match options.mode {
+        RequestMode::Octet => data.len(),
+        _ => netascii::encoded_len(data),
     };
-    // Create the chunk vec for our data
-    let chunks: Vec<_> = data.chunks(BLKSIZE).collect();
-    let mut last_block_n = -1;
-    // Run the state machine
+    upload_inner(
+        filename,
+        Cursor::new(data),
+        Some(size as u64),
+        socket,
+        server,
+        new_policy,
+        observer,
+        options,
+    )?;
+    Ok(())
+}
+
+/// Upload a file via tftp, negotiating `options` with the server and reading the data from
+/// `reader` one block at a time until it runs out
+///
+/// As the size isn't known up front, the `tsize` option isn't sent. Returns the number of bytes
+/// read.
+#[allow(clippy::too_many_arguments)]
+pub fn upload_from<T: AsRef<str> + std::fmt::Display, R: Read>(
+    filename: T,
+    reader: R,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    upload_inner(
+        filename,
+        ReadSource(reader),
+        None,
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+}
+
+/// Where uploaded data comes from, which lets us start over when uploading a slice
+pub(crate) trait Source: Read {
+    /// Go back to the start of the data, if we can
+    fn rewind(&mut self) -> bool {
+        false
+    }
+}
+
+impl Source for Cursor<&[u8]> {
+    fn rewind(&mut self) -> bool {
+        self.set_position(0);
+        true
+    }
+}
+
+pub(crate) struct ReadSource<R>(pub(crate) R);
+
+impl<R: Read> Read for ReadSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> Source for ReadSource<R> {}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn upload_inner<T: AsRef<str> + std::fmt::Display, R: Source>(
+    filename: T,
+    mut source: R,
+    size: Option<u64>,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    new_policy: &NewPolicy<'_>,
+    observer: Option<&Arc<dyn Observer>>,
+    options: &Options,
+) -> Result<u64, Error> {
+    // The deadline covers the whole transfer, however many times it starts over
+    let started = Instant::now();
+    let mut attempts = 0;
     loop {
-        match state {
-            State::Send => {
-                local_retries = retries;
-                local_timeout = timeout;
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt}");
-                // Send the bytes and reset some other state variables
-                socket.send_to(&bytes, server).map_err(Error::SocketIo)?;
-                // Transition to recv if this wasn't the last ACK packet
-                state = State::Recv;
-            }
-            State::SendAgain => {
-                let bytes = send_pkt.to_bytes();
-                debug!("│ TX - {send_pkt} (Retry)");
-                // Send the bytes and reset some other state variables
-                socket.send_to(&bytes, server).map_err(Error::SocketIo)?;
-                // Transition to recv
-                state = State::Recv
-            }
-            State::Recv => {
-                let mut buf = vec![0; BLKSIZE + 4];
-                let n = match socket.recv_from(&mut buf) {
-                    Ok((n, remote_addr)) => {
-                        // Set the server's address as it may have changed ports (as the spec
-                        // allows)
-                        server = remote_addr;
-                        n
-                    }
-                    Err(e) => {
-                        match e.kind() {
-                            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
-                                debug!("│ Timeout");
-                                // We timed out, try sending the last packet again with exponential
-                                // backoff
-                                local_retries -= 1;
-                                if local_retries == 0 {
-                                    return Err(Error::Timeout);
-                                }
-                                local_timeout += local_timeout / 2;
-                                if local_timeout > max_timeout {
-                                    local_timeout = max_timeout;
-                                }
-                                socket
-                                    .set_read_timeout(Some(local_timeout))
-                                    .map_err(Error::SocketIo)?;
-                                state = State::SendAgain;
-                                continue;
-                            }
-                            _ => return Err(Error::SocketIo(e)),
-                        }
-                    }
-                };
-                // Process the received packet
-                let recv_pkt = Packet::from_bytes(&buf[..n]).map_err(Error::Parse)?;
-                debug!("│ RX - {recv_pkt}");
-                match recv_pkt {
-                    Packet::Acknowledgment { block_n } => {
-                        // Fix for https://en.wikipedia.org/wiki/Sorcerer%27s_Apprentice_Syndrome
-                        // Just try to recv again and don't resend the data on duplicate Acks
-                        if last_block_n == -1 {
-                            // Initial block
-                            last_block_n = block_n as i16
-                        } else if last_block_n == block_n as i16 {
-                            state = State::Recv;
-                            continue;
-                        } else {
-                            last_block_n = block_n as i16;
-                        }
-                        // We got back an ack, we need to send out that ack's chunk of data
-                        if block_n as usize == chunks.len() {
-                            break;
-                        }
-                        send_pkt = Packet::Data {
-                            block_n: block_n + 1,
-                            data: chunks[block_n as usize].into(),
-                        };
-                        state = State::Send;
-                        continue;
-                    }
-                    Packet::Error { code, msg } => {
-                        return Err(Error::Protocol {
-                            code,
-                            msg: msg.into_string().expect("Error message had invalid UTF-8"),
-                        })
-                    }
-                    _ => return Err(Error::UnexpectedPacket(recv_pkt)),
-                }
+        let mut read = 0;
+        let policy = new_policy();
+        match upload_attempt(
+            &filename,
+            &mut source,
+            &mut read,
+            size,
+            socket,
+            server,
+            policy,
+            observer,
+            started,
+            options,
+        ) {
+            Err(e)
+                if start_over(options, started, attempts, &e) && (read == 0 || source.rewind()) =>
+            {
+                attempts += 1;
+                debug!(
+                    "└ Starting over ({attempts}/{}) - {e}",
+                    options.transfer_retries
+                );
             }
+            res => return res,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_attempt<T: AsRef<str> + std::fmt::Display, R: Read>(
+    filename: T,
+    reader: &mut R,
+    read: &mut u64,
+    size: Option<u64>,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    observer: Option<&Arc<dyn Observer>>,
+    started: Instant,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut writer = TftpWriter::with_size(
+        filename,
+        size,
+        socket,
+        server,
+        policy,
+        observer,
+        Some(started),
+        options,
+    )?;
+    let mut buf = vec![0; writer.transfer.blksize()];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(writer.abort(Error::Read(e))),
+        };
+        *read += n as u64;
+        writer.push(&buf[..n])?;
+    }
+    // And return how much we uploaded
+    writer.finish()
+}
+
+/// An upload via tftp that implements [`Write`]
+///
+/// The WRQ is sent when the writer is created, and every block is sent as soon as it fills up.
+/// As only the final block may be short, [`flush`](Write::flush) does nothing and the transfer
+/// has to be completed with [`finish`](TftpWriter::finish), otherwise the upload is abandoned
+/// (with an ERROR to the server) when the writer is dropped.
+pub struct TftpWriter<'a> {
+    socket: &'a UdpSocket,
+    transfer: WriteTransfer,
+    // The socket timeout to put back once we're done, if we've changed it
+    old_read_timeout: Option<Option<Duration>>,
+    // Once the transfer fails, every write after (and finishing) gets the same error
+    failed: Option<Error>,
+}
+
+impl<'a> TftpWriter<'a> {
+    /// Start uploading a file via tftp, negotiating `options` with the server
+    ///
+    /// As the size isn't known up front, the `tsize` option isn't sent
+    pub fn new<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        timeout: Duration,
+        max_timeout: Duration,
+        retries: usize,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let policy = Exponential::new(timeout, max_timeout, retries);
+        Self::with_policy(filename, socket, server, Box::new(policy), options)
+    }
+
+    /// Start uploading a file via tftp, negotiating `options` with the server and retrying as
+    /// `policy` sees fit
+    ///
+    /// As the size isn't known up front, the `tsize` option isn't sent
+    pub fn with_policy<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        Self::with_size(filename, None, socket, server, policy, None, None, options)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn with_size<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        size: Option<u64>,
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        observer: Option<&Arc<dyn Observer>>,
+        started: Option<Instant>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let mut writer = Self {
+            socket,
+            transfer: WriteTransfer::with_policy(filename, size, server, policy, options)?,
+            // Make sure we can actually timeout, but preserve the old state
+            old_read_timeout: Some(socket.read_timeout().map_err(Error::SocketIo)?),
+            failed: None,
+        };
+        if let Some(observer) = observer {
+            writer.transfer.observe(observer.clone());
+        }
+        if let Some(started) = started {
+            writer.transfer.started_at(started);
         }
+        // Wait for the server to ack the request before we start sending data
+        writer.drive()?;
+        Ok(writer)
     }
-    debug!("└");
-    // Return socket timeout to previous state
-    socket
+
+    /// Add `data` to the upload, sending out any blocks that fill up
+    fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(error) = &self.failed {
+            return Err(error.again());
+        }
+        self.transfer.write(data);
+        // Once the window is full, we have to hear back from the server before taking more
+        self.drive()
+    }
+
+    /// Send the final (short, or even empty) block and wait for the server to acknowledge
+    /// everything, returning the number of bytes written
+    pub fn finish(mut self) -> Result<u64, Error> {
+        if let Some(error) = &self.failed {
+            return Err(error.again());
+        }
+        self.transfer.finish();
+        self.drive()?;
+        self.restore()?;
+        Ok(self.transfer.written())
+    }
+
+    /// Give up on the transfer because of `error`, letting the server know
+    fn abort(&mut self, error: Error) -> Error {
+        let error = abort(self.socket, &mut self.transfer, error);
+        let _ = self.restore();
+        self.failed = Some(error.again());
+        error
+    }
+
+    /// Run the transfer until it can take more data (or it's done)
+    fn drive(&mut self) -> Result<(), Error> {
+        let res = drive(self.socket, &mut self.transfer, |transfer| {
+            transfer.is_done() || transfer.wants_data()
+        });
+        if let Err(error) = &res {
+            self.failed = Some(error.again());
+        }
+        res
+    }
+
+    /// Put the socket back the way we found it
+    fn restore(&mut self) -> Result<(), Error> {
+        if let Some(old_read_timeout) = self.old_read_timeout.take() {
+            // Return socket timeout to previous state
+            self.socket
+                .set_read_timeout(old_read_timeout)
+                .map_err(Error::SocketIo)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for TftpWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.push(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // Blocks are sent as soon as they're full, and a short one would end the transfer
+        Ok(())
+    }
+}
+
+impl Drop for TftpWriter<'_> {
+    fn drop(&mut self) {
+        // If the transfer was cut short, let the server know rather than leaving it to
+        // retransmit until it times out
+        if self.failed.is_none() && !self.transfer.is_done() {
+            let error = std::io::Error::other("The upload was dropped before it was finished");
+            self.abort(Error::Read(error));
+        }
+        // Put the socket back the way we found it
+        let _ = self.restore();
+    }
+}
+
+/// Query the size of a file via tftp without downloading it
+///
+/// This sends a RRQ with the `tsize` option as per
+/// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349) and aborts the transfer as soon as
+/// the server acknowledges it
+pub fn query_size<T: AsRef<str> + std::fmt::Display>(
+    filename: T,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+) -> Result<u64, Error> {
+    let policy = Exponential::new(timeout, max_timeout, retries);
+    query_size_inner(
+        filename,
+        socket,
+        server,
+        Box::new(policy),
+        &Options::default(),
+    )
+}
+
+pub(crate) fn query_size_inner<T: AsRef<str> + std::fmt::Display>(
+    filename: T,
+    socket: &UdpSocket,
+    server: SocketAddr,
+    policy: Box<dyn RetryPolicy>,
+    options: &Options,
+) -> Result<u64, Error> {
+    let mut transfer = ReadTransfer::query_size(filename, server, policy, options)?;
+    // Make sure we can actually timeout, but preserve the old state
+    let old_read_timeout = socket.read_timeout().map_err(Error::SocketIo)?;
+    let res = drive(socket, &mut transfer, |_| false);
+    // Return socket timeout to previous state, however the query went
+    let restored = socket
         .set_read_timeout(old_read_timeout)
-        .map_err(Error::SocketIo)?;
-    // And return and ok
-    Ok(())
+        .map_err(Error::SocketIo);
+    res?;
+    restored?;
+    transfer
+        .transfer_size()
+        .ok_or_else(|| Error::UnsupportedOption("tsize".to_owned()))
 }
@@ -0,0 +1,399 @@
+//! A reusable client that holds on to its configuration
+
+use std::{
+    io::{
+        Read,
+        Write,
+    },
+    net::{
+        SocketAddr,
+        UdpSocket,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    blocking,
+    parser::RequestMode,
+    retry::{
+        Exponential,
+        RetryPolicy,
+    },
+    transfer::Observer,
+    Error,
+    Options,
+    TftpReader,
+    TftpWriter,
+};
+
+/// Everything a transfer needs to know besides the file, shared by the blocking and async clients
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    pub(crate) server: SocketAddr,
+    pub(crate) timeout: Duration,
+    pub(crate) max_timeout: Duration,
+    pub(crate) retries: usize,
+    pub(crate) options: Options,
+    pub(crate) retry_policy: Option<SharedPolicy>,
+    pub(crate) observer: Option<Arc<dyn Observer>>,
+}
+
+impl Settings {
+    /// A fresh retry policy for a transfer
+    pub(crate) fn policy(&self) -> Box<dyn RetryPolicy> {
+        match &self.retry_policy {
+            Some(new_policy) => (new_policy.0)(),
+            None => Box::new(Exponential::new(
+                self.timeout,
+                self.max_timeout,
+                self.retries,
+            )),
+        }
+    }
+}
+
+/// Makes a copy of the client's retry policy for every transfer
+#[derive(Clone)]
+pub(crate) struct SharedPolicy(Arc<dyn Fn() -> Box<dyn RetryPolicy> + Send + Sync>);
+
+impl std::fmt::Debug for SharedPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SharedPolicy").field(&(self.0)()).finish()
+    }
+}
+
+/// Builds a [`Client`] (or an async one), checking the configuration along the way
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    bind: SocketAddr,
+    settings: Settings,
+}
+
+impl ClientBuilder {
+    /// Start building a client for the server at `server`
+    pub fn new(server: SocketAddr) -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            settings: Settings {
+                server,
+                timeout: Duration::from_secs(1),
+                max_timeout: Duration::from_secs(8),
+                retries: 8,
+                options: Options::default(),
+                retry_policy: None,
+                observer: None,
+            },
+        }
+    }
+
+    /// The local address to bind to, which defaults to any port on any interface
+    pub fn bind(mut self, bind: SocketAddr) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// How long to wait for the server before retransmitting, backing off exponentially from
+    /// there
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.timeout = timeout;
+        self
+    }
+
+    /// The longest we'll ever wait before retransmitting
+    pub fn max_timeout(mut self, max_timeout: Duration) -> Self {
+        self.settings.max_timeout = max_timeout;
+        self
+    }
+
+    /// How many times to retransmit before giving up
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.settings.retries = retries;
+        self
+    }
+
+    /// Decide how long to wait and when to give up with `policy` instead, which replaces the
+    /// timeouts and number of retries
+    ///
+    /// Every transfer starts out with its own copy of `policy`
+    pub fn retry_policy<P: RetryPolicy + Clone + Sync + 'static>(mut self, policy: P) -> Self {
+        self.settings.retry_policy = Some(SharedPolicy(Arc::new(move || Box::new(policy.clone()))));
+        self
+    }
+
+    /// Tell `observer` about every [`Event`](crate::transfer::Event) of every transfer, to show
+    /// progress
+    ///
+    /// ```no_run
+    /// use tftp_client::{
+    ///     transfer::Event,
+    ///     Client,
+    /// };
+    ///
+    /// let client = Client::builder("192.168.0.3:69".parse().unwrap())
+    ///     .observer(|event: &Event| {
+    ///         if let Event::Block {
+    ///             bytes,
+    ///             total: Some(total),
+    ///             ..
+    ///         } = event
+    ///         {
+    ///             println!("{bytes}/{total}");
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.settings.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Block size to request as per [RFC 2348](https://datatracker.ietf.org/doc/html/rfc2348)
+    pub fn blksize(mut self, blksize: u16) -> Self {
+        self.settings.options.blksize = Some(blksize);
+        self
+    }
+
+    /// The transfer mode
+    pub fn mode(mut self, mode: RequestMode) -> Self {
+        self.settings.options.mode = mode;
+        self
+    }
+
+    /// The options to use for every transfer, replacing any block size or mode set so far
+    pub fn options(mut self, options: Options) -> Self {
+        self.settings.options = options;
+        self
+    }
+
+    /// Check the configuration, and bind the socket for a blocking client
+    pub fn build(self) -> Result<Client, Error> {
+        let bind = self.bind;
+        let settings = self.validate()?;
+        Ok(Client {
+            socket: UdpSocket::bind(bind).map_err(Error::SocketIo)?,
+            settings,
+        })
+    }
+
+    /// Where the socket should be bound, for the async clients
+    #[cfg(any(feature = "async", feature = "tokio"))]
+    pub(crate) fn bind_addr(&self) -> SocketAddr {
+        self.bind
+    }
+
+    /// Make sure the settings make sense before we use them
+    pub(crate) fn validate(self) -> Result<Settings, Error> {
+        let settings = self.settings;
+        // A retry policy of our own replaces the timeouts, so there's nothing to check
+        if settings.retry_policy.is_some() {
+            settings.options.to_request(Some(0))?;
+            return Ok(settings);
+        }
+        if settings.retries == 0 {
+            return Err(Error::InvalidConfig(
+                "retries must be at least 1".to_owned(),
+            ));
+        }
+        if settings.timeout.is_zero() {
+            return Err(Error::InvalidConfig(
+                "timeout must be more than zero".to_owned(),
+            ));
+        }
+        if settings.max_timeout < settings.timeout {
+            return Err(Error::InvalidConfig(
+                "max_timeout must be at least timeout".to_owned(),
+            ));
+        }
+        // The options are checked when they're turned into a request
+        settings.options.to_request(Some(0))?;
+        Ok(settings)
+    }
+}
+
+/// A blocking TFTP client, which applies the same configuration to every transfer
+///
+/// ```no_run
+/// use tftp_client::Client;
+///
+/// let client = Client::builder("192.168.0.3:69".parse().unwrap())
+///     .blksize(1428)
+///     .build()
+///     .unwrap();
+/// let bytes = client.get("/temp").unwrap();
+/// client.put("/temp", &bytes).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    socket: UdpSocket,
+    settings: Settings,
+}
+
+impl Client {
+    /// Start building a client for the server at `server`
+    pub fn builder(server: SocketAddr) -> ClientBuilder {
+        ClientBuilder::new(server)
+    }
+
+    /// The socket the client sends from
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Download a file
+    pub fn get<T: AsRef<str> + std::fmt::Display>(&self, filename: T) -> Result<Vec<u8>, Error> {
+        let s = &self.settings;
+        let mut file_data = vec![];
+        blocking::download_into(
+            filename,
+            &mut file_data,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )?;
+        Ok(file_data)
+    }
+
+    /// Download a file, writing each block to `writer` as soon as it arrives
+    ///
+    /// Returns the number of bytes written
+    pub fn get_to<T: AsRef<str> + std::fmt::Display, W: Write>(
+        &self,
+        filename: T,
+        writer: W,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        blocking::download_into(
+            filename,
+            &mut blocking::WriteSink(writer),
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+    }
+
+    /// Start a download that implements [`Read`]
+    pub fn reader<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        filename: T,
+    ) -> Result<TftpReader<'_>, Error> {
+        let s = &self.settings;
+        let mut reader =
+            TftpReader::with_policy(filename, &self.socket, s.server, s.policy(), &s.options)?;
+        if let Some(observer) = &s.observer {
+            reader.observe(observer.clone());
+        }
+        Ok(reader)
+    }
+
+    /// Upload a file
+    pub fn put<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        filename: T,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let s = &self.settings;
+        blocking::upload_slice(
+            filename,
+            data,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+    }
+
+    /// Upload a file, reading the data from `reader` one block at a time until it runs out
+    ///
+    /// Returns the number of bytes read
+    pub fn put_from<T: AsRef<str> + std::fmt::Display, R: Read>(
+        &self,
+        filename: T,
+        reader: R,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        blocking::upload_inner(
+            filename,
+            blocking::ReadSource(reader),
+            None,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+    }
+
+    /// Start an upload that implements [`Write`]
+    pub fn writer<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        filename: T,
+    ) -> Result<TftpWriter<'_>, Error> {
+        let s = &self.settings;
+        TftpWriter::with_size(
+            filename,
+            None,
+            &self.socket,
+            s.server,
+            s.policy(),
+            s.observer.as_ref(),
+            None,
+            &s.options,
+        )
+    }
+
+    /// Query the size of a file without downloading it
+    pub fn size<T: AsRef<str> + std::fmt::Display>(&self, filename: T) -> Result<u64, Error> {
+        let s = &self.settings;
+        blocking::query_size_inner(filename, &self.socket, s.server, s.policy(), &s.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> ClientBuilder {
+        Client::builder("127.0.0.1:69".parse().unwrap())
+    }
+
+    #[test]
+    fn test_no_retries() {
+        assert!(matches!(
+            builder().retries(0).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_timeout_too_short() {
+        assert!(matches!(
+            builder()
+                .timeout(Duration::from_secs(2))
+                .max_timeout(Duration::from_secs(1))
+                .build(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        // The policy replaces the retries, so they're not checked
+        let policy = crate::retry::Constant::new(Duration::from_secs(1), 4);
+        assert!(builder().retries(0).retry_policy(policy).build().is_ok());
+    }
+
+    #[test]
+    fn test_bad_blksize() {
+        assert!(matches!(
+            builder().blksize(4).build(),
+            Err(Error::BadOptionValue(_))
+        ));
+    }
+}
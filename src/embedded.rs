@@ -0,0 +1,706 @@
+//! A `no_std` TFTP client for [`embedded-nal`](https://docs.rs/embedded-nal) network stacks
+//!
+//! Nothing here allocates, packets are built in (and received into) a buffer supplied by the
+//! caller and time comes from a [`Clock`] the caller provides. Transfers are in octet mode, and
+//! the only option that can be negotiated is the block size.
+//!
+//! As sockets from a [`UdpClientStack`] are connected, the socket is connected again to the
+//! server's transfer ID once it responds. This needs a stack that passes along that first
+//! response (which comes from a different port than the request went to) and keeps the same
+//! local port when connecting again.
+
+use core::{
+    net::SocketAddr,
+    time::Duration,
+};
+
+use embedded_nal::{
+    nb,
+    UdpClientStack,
+};
+use thiserror::Error;
+
+use crate::{
+    parser::{
+        self,
+        ErrorCode,
+        PacketRef,
+        RawOptions,
+        RequestMode,
+    },
+    retry::{
+        Exponential,
+        RetryPolicy,
+    },
+    Rollover,
+    BLKSIZE,
+};
+
+/// A monotonic clock
+pub trait Clock {
+    /// The time since some fixed point in the past
+    fn now(&mut self) -> Duration;
+}
+
+/// How a transfer should run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// How long to wait for the server before retransmitting, backing off exponentially from there
+    pub timeout: Duration,
+    /// The longest we'll ever wait before retransmitting
+    pub max_timeout: Duration,
+    /// How many times to retransmit before giving up
+    pub retries: usize,
+    /// Block size to request as per [RFC 2348](https://datatracker.ietf.org/doc/html/rfc2348),
+    /// which the buffer has to have room for
+    pub blksize: Option<u16>,
+    /// Only accept replies from the IP address the request was sent to. Whatever the address,
+    /// the transfer is locked to the server's transfer ID once it first replies, and datagrams
+    /// from anywhere else are ignored.
+    pub strict_host: bool,
+    /// The block number that follows 65535, which has to match the server for transfers of more
+    /// than 65535 blocks
+    pub rollover: Rollover,
+    /// How long to keep listening after acknowledging the final block of a download, so the ack
+    /// can be sent again if the server didn't get it and resends the block
+    pub dally: Option<Duration>,
+    /// How long the whole transfer may take before giving up with [`Error::Timeout`], no matter
+    /// how many retries are left
+    pub deadline: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            max_timeout: Duration::from_secs(8),
+            retries: 8,
+            blksize: None,
+            strict_host: false,
+            rollover: Rollover::Zero,
+            dally: None,
+            deadline: None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error<N, D> {
+    #[error("Network stack error - `{0:?}`")]
+    Network(N),
+    #[error("Failed to handle the data - `{0:?}`")]
+    Data(D),
+    #[error("Bad filename (contains a null byte)")]
+    BadFilename,
+    #[error("The buffer doesn't have room for a whole packet")]
+    BufferTooSmall,
+    #[error("Timeout while trying to complete transaction")]
+    Timeout,
+    #[error("Failed to parse incoming packet - `{0}`")]
+    Parse(parser::Error),
+    #[error("The packet we got back was unexpected")]
+    UnexpectedPacket,
+    #[error("The protocol itself gave us an error with code `{0:?}`")]
+    Protocol(ErrorCode),
+    #[error("The server acknowledged an option we never requested, or with an invalid value")]
+    BadOption,
+}
+
+/// Room for the only option we request, `blksize\0<up to 5 digits>\0`
+const OPTIONS_LEN: usize = 14;
+
+/// Serialize the `blksize` option (if we're requesting one) into `buf`
+fn request_options(blksize: Option<u16>, buf: &mut [u8; OPTIONS_LEN]) -> RawOptions<'_> {
+    let Some(mut blksize) = blksize else {
+        return RawOptions::default();
+    };
+    buf[..8].copy_from_slice(b"blksize\0");
+    let mut digits = [0; 5];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (blksize % 10) as u8;
+        blksize /= 10;
+        if blksize == 0 {
+            break;
+        }
+    }
+    let len = 8 + digits.len() - i;
+    buf[8..len].copy_from_slice(&digits[i..]);
+    buf[len] = 0;
+    RawOptions::new(&buf[..=len]).expect("We serialized the option ourselves")
+}
+
+/// Check the options the server acknowledged, returning the block size to use
+fn negotiate<N, D>(requested: Option<u16>, acknowledged: RawOptions) -> Result<usize, Error<N, D>> {
+    let mut blksize = BLKSIZE;
+    for (name, value) in acknowledged.iter() {
+        // The server may pick a smaller block size than we asked for, but never a larger one
+        let value = core::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<u16>().ok());
+        match (name.eq_ignore_ascii_case(b"blksize"), requested, value) {
+            (true, Some(requested), Some(value)) if (8..=requested).contains(&value) => {
+                blksize = value as usize
+            }
+            _ => return Err(Error::BadOption),
+        }
+    }
+    Ok(blksize)
+}
+
+fn encode_error<N, D>(e: parser::Error) -> Error<N, D> {
+    match e {
+        parser::Error::BufferTooSmall(_) => Error::BufferTooSmall,
+        _ => Error::BadFilename,
+    }
+}
+
+/// The connection to the server over a socket from `stack`, which keeps track of retries
+struct Link<'s, S: UdpClientStack, C> {
+    stack: &'s mut S,
+    socket: &'s mut S::UdpSocket,
+    clock: &'s mut C,
+    server: SocketAddr,
+    // Keep track of the original address in case we need to send the request again
+    initial_server: SocketAddr,
+    // Whether the server's transfer ID is locked in, which happens with its first reply
+    tid_locked: bool,
+    config: &'s Config,
+    policy: &'s mut dyn RetryPolicy,
+    // When the transfer started and gives up, and when we last sent something
+    started: Duration,
+    last_sent: Duration,
+    // Whether we've sent anything again since we last made progress, which makes the round-trip
+    // time ambiguous
+    retransmitted: bool,
+}
+
+impl<'s, S: UdpClientStack, C: Clock> Link<'s, S, C> {
+    fn new(
+        stack: &'s mut S,
+        socket: &'s mut S::UdpSocket,
+        clock: &'s mut C,
+        server: SocketAddr,
+        config: &'s Config,
+        policy: &'s mut dyn RetryPolicy,
+    ) -> Self {
+        let started = clock.now();
+        Self {
+            stack,
+            socket,
+            clock,
+            server,
+            initial_server: server,
+            tid_locked: false,
+            config,
+            policy,
+            started,
+            last_sent: started,
+            retransmitted: false,
+        }
+    }
+
+    fn send<D>(&mut self, datagram: &[u8]) -> Result<(), Error<S::Error, D>> {
+        self.last_sent = self.clock.now();
+        nb::block!(self.stack.send(self.socket, datagram)).map_err(Error::Network)
+    }
+
+    /// Send `datagram` again, after which the round-trip time is ambiguous
+    fn send_again<D>(&mut self, datagram: &[u8]) -> Result<(), Error<S::Error, D>> {
+        self.retransmitted = true;
+        self.send(datagram)
+    }
+
+    /// We're making progress, so undo any backoff
+    fn progress(&mut self) {
+        // Following Karn's algorithm, only replies to packets we sent once tell us the round-trip
+        // time
+        let rtt = self.clock.now().saturating_sub(self.last_sent);
+        self.policy
+            .on_progress((!self.retransmitted).then_some(rtt));
+        self.retransmitted = false;
+    }
+
+    /// We timed out, so wait longer for the retry (or give up)
+    fn backoff<D>(&mut self) -> Result<(), Error<S::Error, D>> {
+        if !self.policy.on_timeout() {
+            return Err(Error::Timeout);
+        }
+        // Whatever we send next is a retransmission
+        self.retransmitted = true;
+        Ok(())
+    }
+
+    /// Point the socket at `server`
+    fn connect<D>(&mut self, server: SocketAddr) -> Result<(), Error<S::Error, D>> {
+        self.server = server;
+        self.stack
+            .connect(self.socket, server)
+            .map_err(Error::Network)
+    }
+
+    /// Tell the server why we're giving up, if it's listening and didn't give up first
+    fn abort<D>(&mut self, error: &Error<S::Error, D>) {
+        let (code, msg): (_, &[u8]) = match error {
+            // Until the server replies, there's no transfer for it to abort
+            _ if !self.tid_locked => return,
+            Error::Protocol(_) | Error::BadFilename | Error::BufferTooSmall => return,
+            Error::Data(_) => (ErrorCode::Unspec, b"Failed to handle the data"),
+            Error::Parse(_) | Error::UnexpectedPacket => (ErrorCode::Op, b"Unexpected packet"),
+            Error::BadOption => (ErrorCode::BadOpt, b"Bad option"),
+            Error::Network(_) => (ErrorCode::Unspec, b"Network error"),
+            Error::Timeout => (ErrorCode::Unspec, b"Timed out"),
+        };
+        let mut bytes = [0; 32];
+        let n = PacketRef::Error { code, msg }
+            .encode(&mut bytes)
+            .expect("The messages are short");
+        // We're already failing, so there's nothing to be done if this doesn't get through
+        let _ = nb::block!(self.stack.send(self.socket, &bytes[..n]));
+    }
+
+    /// Go back to the start after the server refused our options
+    fn restart<D>(&mut self) -> Result<(), Error<S::Error, D>> {
+        self.progress();
+        self.tid_locked = false;
+        self.connect(self.initial_server)
+    }
+
+    /// Wait for a packet for as long as the retry policy says, returning `None` if we timed out
+    fn recv<'b, D>(
+        &mut self,
+        buf: &'b mut [u8],
+    ) -> Result<Option<PacketRef<'b>>, Error<S::Error, D>> {
+        let timeout = self.policy.timeout();
+        self.recv_for(timeout, buf)
+    }
+
+    /// Wait up to `timeout` for a packet, returning `None` if we timed out
+    fn recv_for<'b, D>(
+        &mut self,
+        timeout: Duration,
+        buf: &'b mut [u8],
+    ) -> Result<Option<PacketRef<'b>>, Error<S::Error, D>> {
+        let now = self.clock.now();
+        let mut deadline = now + timeout;
+        if let Some(transfer_deadline) = self.config.deadline.map(|d| self.started + d) {
+            if now >= transfer_deadline {
+                return Err(Error::Timeout);
+            }
+            deadline = deadline.min(transfer_deadline);
+        }
+        self.recv_until(deadline, buf)
+    }
+
+    /// Wait until `deadline` for a packet, returning `None` if we timed out
+    fn recv_until<'b, D>(
+        &mut self,
+        deadline: Duration,
+        buf: &'b mut [u8],
+    ) -> Result<Option<PacketRef<'b>>, Error<S::Error, D>> {
+        loop {
+            match self.stack.receive(self.socket, buf) {
+                Ok((n, source)) => {
+                    let foreign = if self.tid_locked {
+                        source != self.server
+                    } else {
+                        self.config.strict_host && source.ip() != self.initial_server.ip()
+                    };
+                    // Datagrams from anyone else don't disturb the transfer. As the socket is
+                    // connected to the server, we can't send the sender an error though.
+                    if foreign {
+                        continue;
+                    }
+                    let pkt = PacketRef::from_bytes(&buf[..n]).map_err(Error::Parse)?;
+                    // The server picks a new port for the transfer (as the spec allows), which we
+                    // hold it to from then on, but only once it sends us something that makes
+                    // sense
+                    if source != self.server {
+                        self.connect(source)?;
+                    }
+                    self.tid_locked = true;
+                    return Ok(Some(pkt));
+                }
+                Err(nb::Error::WouldBlock) => {
+                    if self.clock.now() >= deadline {
+                        return Ok(None);
+                    }
+                }
+                Err(nb::Error::Other(e)) => return Err(Error::Network(e)),
+            }
+        }
+    }
+}
+
+/// Run `f` with a new socket from `stack`, making sure the socket is closed afterwards
+fn with_socket<S: UdpClientStack, D, T>(
+    stack: &mut S,
+    server: SocketAddr,
+    f: impl FnOnce(&mut S, &mut S::UdpSocket) -> Result<T, Error<S::Error, D>>,
+) -> Result<T, Error<S::Error, D>> {
+    let mut socket = stack.socket().map_err(Error::Network)?;
+    let res = match stack.connect(&mut socket, server) {
+        Ok(()) => f(stack, &mut socket),
+        Err(e) => Err(Error::Network(e)),
+    };
+    let closed = stack.close(socket);
+    let res = res?;
+    closed.map_err(Error::Network)?;
+    Ok(res)
+}
+
+/// Download a file via tftp, passing each block to `sink` as soon as it arrives
+///
+/// `buf` has to have room for a whole DATA packet, which is 4 bytes more than the block size.
+/// Returns the number of bytes downloaded.
+pub fn download<S, C, D, F>(
+    stack: &mut S,
+    clock: &mut C,
+    server: SocketAddr,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    sink: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&[u8]) -> Result<(), D>,
+{
+    let mut policy = Exponential::new(config.timeout, config.max_timeout, config.retries);
+    download_with_policy(
+        stack,
+        clock,
+        server,
+        filename,
+        buf,
+        config,
+        &mut policy,
+        sink,
+    )
+}
+
+/// Like [`download`], but deciding how long to wait and when to give up with `policy` instead of
+/// the timeouts and number of retries in `config`
+#[allow(clippy::too_many_arguments)]
+pub fn download_with_policy<S, C, D, F>(
+    stack: &mut S,
+    clock: &mut C,
+    server: SocketAddr,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    policy: &mut dyn RetryPolicy,
+    sink: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&[u8]) -> Result<(), D>,
+{
+    if buf.len() < config.blksize.map_or(BLKSIZE, usize::from) + 4 {
+        return Err(Error::BufferTooSmall);
+    }
+    with_socket(stack, server, |stack, socket| {
+        let mut link = Link::new(stack, socket, clock, server, config, policy);
+        let res = download_inner(&mut link, filename, buf, config, sink);
+        if let Err(e) = &res {
+            link.abort(e);
+        }
+        res
+    })
+}
+
+fn ack_bytes(block_n: u16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    PacketRef::Acknowledgment { block_n }
+        .encode(&mut bytes)
+        .expect("An ACK is always 4 bytes");
+    bytes
+}
+
+/// Upload a file via tftp, with `source` filling the buffer it's given with data until it runs out
+/// (returning 0)
+///
+/// `buf` has to have room for a whole DATA packet, which is 4 bytes more than the block size.
+/// Returns the number of bytes uploaded.
+pub fn upload<S, C, D, F>(
+    stack: &mut S,
+    clock: &mut C,
+    server: SocketAddr,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    source: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&mut [u8]) -> Result<usize, D>,
+{
+    let mut policy = Exponential::new(config.timeout, config.max_timeout, config.retries);
+    upload_with_policy(
+        stack,
+        clock,
+        server,
+        filename,
+        buf,
+        config,
+        &mut policy,
+        source,
+    )
+}
+
+/// Like [`upload`], but deciding how long to wait and when to give up with `policy` instead of
+/// the timeouts and number of retries in `config`
+#[allow(clippy::too_many_arguments)]
+pub fn upload_with_policy<S, C, D, F>(
+    stack: &mut S,
+    clock: &mut C,
+    server: SocketAddr,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    policy: &mut dyn RetryPolicy,
+    source: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&mut [u8]) -> Result<usize, D>,
+{
+    if buf.len() < config.blksize.map_or(BLKSIZE, usize::from) + 4 {
+        return Err(Error::BufferTooSmall);
+    }
+    with_socket(stack, server, |stack, socket| {
+        let mut link = Link::new(stack, socket, clock, server, config, policy);
+        let res = upload_inner(&mut link, filename, buf, config, source);
+        if let Err(e) = &res {
+            link.abort(e);
+        }
+        res
+    })
+}
+
+fn download_inner<S, C, D, F>(
+    link: &mut Link<S, C>,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    mut sink: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&[u8]) -> Result<(), D>,
+{
+    let mut options_buf = [0; OPTIONS_LEN];
+    let mut options = request_options(config.blksize, &mut options_buf);
+    let mut negotiating = config.blksize.is_some();
+    // Until the server acknowledges our options, we use the RFC 1350 block size
+    let mut blksize = BLKSIZE;
+    // Blocks are counted from the start of the transfer, as block numbers wrap around
+    let mut expected_block: u64 = 1;
+    let mut written = 0;
+    // What we sent last, as ACKs are small enough to keep out of the way of the buffer
+    let mut ack: Option<[u8; 4]> = None;
+    let send_request = |link: &mut Link<S, C>, buf: &mut [u8], options| {
+        let n = PacketRef::ReadRequest {
+            filename: filename.as_bytes(),
+            mode: RequestMode::Octet,
+            options,
+        }
+        .encode(buf)
+        .map_err(encode_error)?;
+        link.send(&buf[..n])
+    };
+    send_request(link, buf, options)?;
+    loop {
+        let Some(pkt) = link.recv(buf)? else {
+            // Try sending the last packet again
+            link.backoff()?;
+            match &ack {
+                Some(ack) => link.send(ack)?,
+                None => send_request(link, buf, options)?,
+            }
+            continue;
+        };
+        let block_n = match pkt {
+            PacketRef::Data { block_n, data } => {
+                negotiating = false;
+                let position = config.rollover.position(block_n, expected_block);
+                if position == expected_block {
+                    // We got back the next chunk of data, pass it along
+                    sink(data).map_err(Error::Data)?;
+                    written += data.len() as u64;
+                    expected_block += 1;
+                    if data.len() < blksize {
+                        let ack = ack_bytes(block_n);
+                        link.send(&ack)?;
+                        // Wait around in case the ACK goes missing and the server resends. We
+                        // have the whole file by now, so the deadline no longer applies and
+                        // nothing that goes wrong from here on fails the download.
+                        if let Some(dally) = config.dally {
+                            let until = link.clock.now() + dally;
+                            while let Ok(Some(pkt)) = link.recv_until::<D>(until, buf) {
+                                if let PacketRef::Data {
+                                    block_n: resent, ..
+                                } = pkt
+                                {
+                                    if resent == block_n && link.send::<D>(&ack).is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        return Ok(written);
+                    }
+                    block_n
+                } else if position + 1 == expected_block {
+                    // Our ACK must have gone missing, so send it again. That's no progress, as
+                    // the server is only retransmitting.
+                    if let Some(ack) = &ack {
+                        link.send_again(ack)?;
+                    }
+                    continue;
+                } else if position < expected_block {
+                    // Anything older is a stale duplicate
+                    continue;
+                } else {
+                    // The server can't be ahead of our last ACK
+                    return Err(Error::UnexpectedPacket);
+                }
+            }
+            PacketRef::OptionAcknowledgment { .. } if negotiating && ack.is_some() => {
+                // The server resent its OACK, so our ACK must have gone missing
+                if let Some(ack) = &ack {
+                    link.send_again(ack)?;
+                }
+                continue;
+            }
+            PacketRef::OptionAcknowledgment {
+                options: acknowledged,
+            } if negotiating => {
+                // The server accepted our options, so we acknowledge with block 0 to start
+                // the transfer
+                blksize = negotiate(config.blksize, acknowledged)?;
+                0
+            }
+            PacketRef::Error {
+                code: ErrorCode::BadOpt,
+                ..
+            } if negotiating && ack.is_none() => {
+                // The server refused our options, so fall back to a plain RFC 1350 request
+                negotiating = false;
+                options = RawOptions::default();
+                link.restart()?;
+                send_request(link, buf, options)?;
+                continue;
+            }
+            PacketRef::Error { code, .. } => return Err(Error::Protocol(code)),
+            _ => return Err(Error::UnexpectedPacket),
+        };
+        link.progress();
+        let bytes = ack_bytes(block_n);
+        link.send(&bytes)?;
+        ack = Some(bytes);
+    }
+}
+
+fn upload_inner<S, C, D, F>(
+    link: &mut Link<S, C>,
+    filename: &str,
+    buf: &mut [u8],
+    config: &Config,
+    mut source: F,
+) -> Result<u64, Error<S::Error, D>>
+where
+    S: UdpClientStack,
+    C: Clock,
+    F: FnMut(&mut [u8]) -> Result<usize, D>,
+{
+    let mut options_buf = [0; OPTIONS_LEN];
+    let options = request_options(config.blksize, &mut options_buf);
+    let mut negotiating = config.blksize.is_some();
+    // Until the server acknowledges our options, we use the RFC 1350 block size
+    let mut blksize = BLKSIZE;
+    // The last packet we sent is kept at the start of `buf`, in case we need to send it again
+    let mut len = PacketRef::WriteRequest {
+        filename: filename.as_bytes(),
+        mode: RequestMode::Octet,
+        options,
+    }
+    .encode(buf)
+    .map_err(encode_error)?;
+    link.send(&buf[..len])?;
+    // Only ACKs (and OACKs or ERRORs) come back, which fit in the RFC 1350 block size
+    let mut rx = [0; BLKSIZE + 4];
+    // Blocks are counted from the start of the transfer, as block numbers wrap around
+    let mut block: u64 = 0;
+    let mut block_n = 0;
+    let mut read = 0;
+    let mut read_all = false;
+    loop {
+        let Some(pkt) = link.recv(&mut rx)? else {
+            // Try sending the last packet again
+            link.backoff()?;
+            link.send(&buf[..len])?;
+            continue;
+        };
+        match pkt {
+            PacketRef::Acknowledgment { block_n: acked } if acked == block_n => {}
+            PacketRef::OptionAcknowledgment {
+                options: acknowledged,
+            } if negotiating && block == 0 => {
+                // The server accepted our options, which stands in for the ACK of block 0
+                blksize = negotiate(config.blksize, acknowledged)?;
+            }
+            // Fix for https://en.wikipedia.org/wiki/Sorcerer%27s_Apprentice_Syndrome
+            // Just try to recv again and don't resend the data on duplicate (or stale) Acks
+            PacketRef::Acknowledgment { .. } => continue,
+            PacketRef::Error {
+                code: ErrorCode::BadOpt,
+                ..
+            } if negotiating && block == 0 => {
+                // The server refused our options, so fall back to a plain RFC 1350 request
+                negotiating = false;
+                link.restart()?;
+                len = PacketRef::WriteRequest {
+                    filename: filename.as_bytes(),
+                    mode: RequestMode::Octet,
+                    options: RawOptions::default(),
+                }
+                .encode(buf)
+                .map_err(encode_error)?;
+                link.send(&buf[..len])?;
+                continue;
+            }
+            PacketRef::Error { code, .. } => return Err(Error::Protocol(code)),
+            _ => return Err(Error::UnexpectedPacket),
+        }
+        negotiating = false;
+        link.progress();
+        if read_all {
+            return Ok(read);
+        }
+        // Fill up the next block, which is only short at the end of the data
+        let mut filled = 0;
+        while filled < blksize {
+            let n = source(&mut buf[4 + filled..4 + blksize]).map_err(Error::Data)?;
+            if n == 0 {
+                read_all = true;
+                break;
+            }
+            filled += n;
+        }
+        read += filled as u64;
+        block += 1;
+        block_n = config.rollover.block_n(block);
+        // The data is already in place, so we only need the header in front of it
+        buf[..2].copy_from_slice(&3u16.to_be_bytes());
+        buf[2..4].copy_from_slice(&block_n.to_be_bytes());
+        len = 4 + filled;
+        link.send(&buf[..len])?;
+    }
+}
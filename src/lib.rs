@@ -1,30 +1,105 @@
 //! An implementation of the TFTP Client as specified in [RFC 1350](https://datatracker.ietf.org/doc/html/rfc1350)
-//! This includes retries and timeouts with exponential backoff
+//! This includes retries and timeouts with exponential backoff, as well as option negotiation
+//! as specified in [RFC 2347](https://datatracker.ietf.org/doc/html/rfc2347)
+//!
+//! Without the default `std` feature, only the [`parser`] (and the [`embedded`] client, with the
+//! `embedded` feature) are available, in `no_std` and without allocating
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 use thiserror::Error;
 
-#[cfg(feature = "async")]
+#[cfg(any(feature = "async", feature = "tokio"))]
 pub mod asynchronous;
+#[cfg(feature = "std")]
 mod blocking;
+#[cfg(feature = "std")]
+mod client;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+#[cfg(feature = "std")]
+pub mod netascii;
+#[cfg(feature = "std")]
+mod options;
 pub mod parser;
+#[cfg(any(feature = "std", feature = "embedded"))]
+pub mod retry;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+#[cfg(feature = "std")]
+pub mod transfer;
 
 /// The blocking functions are the default
+#[cfg(feature = "std")]
 pub use blocking::*;
+#[cfg(feature = "std")]
+pub use client::{
+    Client,
+    ClientBuilder,
+};
+#[cfg(feature = "std")]
+pub use options::{
+    Options,
+    MAX_BLKSIZE,
+    MIN_BLKSIZE,
+};
 
+/// The RFC 1350 block size, used unless a different one is negotiated
+#[cfg(any(feature = "std", feature = "embedded"))]
 const BLKSIZE: usize = 512;
 
-enum State {
-    Send,
-    SendAgain,
-    Recv,
+/// The block number that follows 65535 in transfers of more than 65535 blocks
+///
+/// RFC 1350 doesn't say, so servers differ. Most (like tftpd-hpa) wrap around to 0, but some
+/// skip 0 as it's only used to acknowledge the request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rollover {
+    #[default]
+    Zero,
+    One,
+}
+
+#[cfg(any(feature = "std", feature = "embedded"))]
+impl Rollover {
+    /// How many blocks there are before the block numbers repeat
+    fn period(self) -> u64 {
+        match self {
+            Rollover::Zero => 1 << 16,
+            Rollover::One => u16::MAX as u64,
+        }
+    }
+
+    /// The block number of the `n`th block of a transfer, where block 0 stands for the request
+    fn block_n(self, n: u64) -> u16 {
+        match self {
+            Rollover::One if n > u16::MAX as u64 => ((n - 1) % self.period() + 1) as u16,
+            _ => n as u16,
+        }
+    }
+
+    /// Which block of a transfer `block_n` is, picking whichever one is closest to the `near`th
+    fn position(self, block_n: u16, near: u64) -> u64 {
+        let base = block_n as u64;
+        if self == Rollover::One && block_n == 0 {
+            return 0;
+        }
+        let laps = (near + self.period() / 2).saturating_sub(base) / self.period();
+        base + laps * self.period()
+    }
 }
 
+#[cfg(feature = "std")]
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Bad filename (not a valid CString)")]
     BadFilename,
     #[error("Socket IO error - `{0}`")]
     SocketIo(std::io::Error),
+    #[error("Failed to write the downloaded data - `{0}`")]
+    Write(std::io::Error),
+    #[error("Failed to read the data to upload - `{0}`")]
+    Read(std::io::Error),
     #[error("Timeout while trying to complete transaction")]
     Timeout,
     #[error("Failed to parse incoming packet - `{0}`")]
@@ -36,4 +111,62 @@ pub enum Error {
         code: parser::ErrorCode,
         msg: String,
     },
+    #[error("The server acknowledged an option we never requested - `{0}`")]
+    UnrequestedOption(String),
+    #[error("The value of option `{0}` was invalid or out of range")]
+    BadOptionValue(String),
+    #[error("The server didn't acknowledge option `{0}`")]
+    UnsupportedOption(String),
+    #[error("Invalid client configuration - {0}")]
+    InvalidConfig(String),
+}
+
+#[cfg(feature = "std")]
+impl Error {
+    /// Whether the transfer might go through if it were started over, as the server stopped
+    /// responding, forgot about the transfer, or started it over itself
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Timeout | Error::UnexpectedPacket(_) => true,
+            Error::Protocol { code, .. } => *code == parser::ErrorCode::BadId,
+            Error::SocketIo(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+            ),
+            _ => false,
+        }
+    }
+
+    /// The same error again, for when it has to be reported more than once
+    pub(crate) fn again(&self) -> Self {
+        let io = |e: &std::io::Error| std::io::Error::new(e.kind(), e.to_string());
+        match self {
+            Error::BadFilename => Error::BadFilename,
+            Error::SocketIo(e) => Error::SocketIo(io(e)),
+            Error::Write(e) => Error::Write(io(e)),
+            Error::Read(e) => Error::Read(io(e)),
+            Error::Timeout => Error::Timeout,
+            Error::Parse(e) => Error::Parse(e.clone()),
+            Error::UnexpectedPacket(pkt) => Error::UnexpectedPacket(pkt.clone()),
+            Error::Protocol { code, msg } => Error::Protocol {
+                code: *code,
+                msg: msg.clone(),
+            },
+            Error::UnrequestedOption(s) => Error::UnrequestedOption(s.clone()),
+            Error::BadOptionValue(s) => Error::BadOptionValue(s.clone()),
+            Error::UnsupportedOption(s) => Error::UnsupportedOption(s.clone()),
+            Error::InvalidConfig(s) => Error::InvalidConfig(s.clone()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::SocketIo(e) | Error::Write(e) | Error::Read(e) => e,
+            Error::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, e),
+            _ => std::io::Error::other(e),
+        }
+    }
 }
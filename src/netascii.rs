@@ -0,0 +1,103 @@
+//! Translation to and from the netascii transfer mode
+//!
+//! Netascii as used by TFTP is the NVT ASCII of [RFC 764](https://datatracker.ietf.org/doc/html/rfc764),
+//! where a newline is sent as CR LF and a bare carriage return is sent as CR NUL
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+/// Encode `input` as netascii, appending the result to `output`
+///
+/// Every byte translates on its own, so this can be called on arbitrary chunks of a file
+pub fn encode(input: &[u8], output: &mut Vec<u8>) {
+    for &byte in input {
+        match byte {
+            LF => output.extend_from_slice(&[CR, LF]),
+            CR => output.extend_from_slice(&[CR, NUL]),
+            _ => output.push(byte),
+        }
+    }
+}
+
+/// The length of `input` once it's encoded as netascii
+pub fn encoded_len(input: &[u8]) -> usize {
+    input.len() + input.iter().filter(|&&b| b == LF || b == CR).count()
+}
+
+/// Decoder from netascii, which keeps track of a CR at the end of one block whose pair starts the
+/// next
+#[derive(Debug, Default)]
+pub struct Decoder {
+    pending_cr: bool,
+}
+
+impl Decoder {
+    /// Decode a chunk of netascii in `input`, appending the result to `output`
+    pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for &byte in input {
+            if self.pending_cr {
+                self.pending_cr = false;
+                match byte {
+                    LF => output.push(LF),
+                    NUL => output.push(CR),
+                    // Not valid netascii, but pass it along instead of dropping data
+                    CR => {
+                        output.push(CR);
+                        self.pending_cr = true;
+                    }
+                    _ => output.extend_from_slice(&[CR, byte]),
+                }
+            } else if byte == CR {
+                self.pending_cr = true;
+            } else {
+                output.push(byte);
+            }
+        }
+    }
+
+    /// Finish decoding, flushing a dangling CR at the very end of the file (if there is one)
+    pub fn finish(self, output: &mut Vec<u8>) {
+        if self.pending_cr {
+            output.push(CR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut output = vec![];
+        encode(b"foo\nbar\rbaz\r\n", &mut output);
+        assert_eq!(output, b"foo\r\nbar\r\0baz\r\0\r\n");
+        assert_eq!(encoded_len(b"foo\nbar\rbaz\r\n"), output.len());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = b"\r\rfoo\n\nbar\r\n\rbaz\r";
+        let mut encoded = vec![];
+        encode(input, &mut encoded);
+        let mut decoder = Decoder::default();
+        let mut decoded = vec![];
+        decoder.decode(&encoded, &mut decoded);
+        decoder.finish(&mut decoded);
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_split_pair() {
+        // The CR of a CR LF pair ends one block and the LF starts the next
+        let mut decoder = Decoder::default();
+        let mut decoded = vec![];
+        decoder.decode(b"foo\r", &mut decoded);
+        assert_eq!(decoded, b"foo");
+        decoder.decode(b"\nbar\r", &mut decoded);
+        decoder.decode(b"\0", &mut decoded);
+        decoder.finish(&mut decoded);
+        assert_eq!(decoded, b"foo\nbar\r");
+    }
+}
@@ -0,0 +1,239 @@
+//! Option negotiation as specified in [RFC 2347](https://datatracker.ietf.org/doc/html/rfc2347)
+
+use std::{
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    parser::{
+        RequestMode,
+        TransferOption,
+    },
+    Error,
+    Rollover,
+    BLKSIZE,
+};
+
+/// Smallest block size allowed by RFC 2348
+pub const MIN_BLKSIZE: u16 = 8;
+/// Largest block size allowed by RFC 2348
+pub const MAX_BLKSIZE: u16 = 65464;
+
+/// The options for a transfer, most of which are negotiated with the server
+///
+/// Servers that don't understand option negotiation will ignore these entirely, and servers
+/// that reject them outright cause the transfer to fall back to plain RFC 1350
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The transfer mode, where netascii is translated to and from CR LF line endings
+    pub mode: RequestMode,
+    /// Block size to request as per [RFC 2348](https://datatracker.ietf.org/doc/html/rfc2348),
+    /// between [`MIN_BLKSIZE`] and [`MAX_BLKSIZE`]
+    pub blksize: Option<u16>,
+    /// Number of blocks to send before waiting for an acknowledgment as per
+    /// [RFC 7440](https://datatracker.ietf.org/doc/html/rfc7440)
+    pub windowsize: Option<u16>,
+    /// Exchange the size of the file as per
+    /// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349), which lets the server refuse
+    /// uploads that won't fit and tells us the size of downloads up front
+    pub tsize: bool,
+    /// Retransmission timeout in seconds (1-255) the server should use as per
+    /// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349). Once accepted, the client
+    /// retransmits on the same interval, backing off from there.
+    pub timeout: Option<u8>,
+    /// Additional options this library doesn't interpret, but should be sent with the request
+    pub extra: Vec<TransferOption>,
+    /// Only accept replies from the IP address the request was sent to. Whatever the address,
+    /// the transfer is locked to the server's transfer ID once it first replies, and datagrams
+    /// from anywhere else are rejected as per RFC 1350.
+    pub strict_host: bool,
+    /// The block number that follows 65535, which has to match the server for transfers of more
+    /// than 65535 blocks
+    pub rollover: Rollover,
+    /// How long to keep listening after acknowledging the final block of a download, so the ack
+    /// can be sent again if the server didn't get it and resends the block
+    pub dally: Option<Duration>,
+    /// How long the whole transfer may take, from sending the request to the final block,
+    /// before giving up with [`Error::Timeout`] no matter how many retries are left
+    pub deadline: Option<Duration>,
+    /// How many times to start the whole transfer over (sending the request again) after it
+    /// fails in a way that might not happen again, as per [`Error::is_transient`]. Whatever was
+    /// downloaded so far is thrown away, so transfers that stream to a writer only start over if
+    /// nothing was written yet, and streamed uploads only if nothing was read yet.
+    pub transfer_retries: usize,
+}
+
+/// The parameters of a transfer after the server has weighed in on our options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Negotiated {
+    pub(crate) blksize: usize,
+    pub(crate) windowsize: usize,
+    pub(crate) tsize: Option<u64>,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl Default for Negotiated {
+    fn default() -> Self {
+        Self {
+            blksize: BLKSIZE,
+            windowsize: 1,
+            tsize: None,
+            timeout: None,
+        }
+    }
+}
+
+impl Options {
+    /// The list of options to append to a RRQ or WRQ, where `size` is the size of the file we're
+    /// uploading (if we know it), or zero for a download
+    pub(crate) fn to_request(&self, size: Option<u64>) -> Result<Vec<TransferOption>, Error> {
+        let mut options = vec![];
+        if let Some(blksize) = self.blksize {
+            if !(MIN_BLKSIZE..=MAX_BLKSIZE).contains(&blksize) {
+                return Err(Error::BadOptionValue("blksize".to_owned()));
+            }
+            options.push(option("blksize", blksize));
+        }
+        if let Some(windowsize) = self.windowsize {
+            if windowsize == 0 {
+                return Err(Error::BadOptionValue("windowsize".to_owned()));
+            }
+            options.push(option("windowsize", windowsize));
+        }
+        if let (true, Some(size)) = (self.tsize, size) {
+            options.push(option("tsize", size));
+        }
+        if let Some(timeout) = self.timeout {
+            if timeout == 0 {
+                return Err(Error::BadOptionValue("timeout".to_owned()));
+            }
+            options.push(option("timeout", timeout));
+        }
+        options.extend(self.extra.iter().cloned());
+        Ok(options)
+    }
+
+    /// Check the options the server acknowledged against the ones we requested, returning the
+    /// parameters the transfer should use
+    pub(crate) fn negotiate(
+        requested: &[TransferOption],
+        acknowledged: &[TransferOption],
+    ) -> Result<Negotiated, Error> {
+        let mut negotiated = Negotiated::default();
+        for opt in acknowledged {
+            // The server is only allowed to acknowledge options we asked for
+            let name = opt.name.to_string_lossy();
+            let Some(req) = requested.iter().find(|req| req.is(&name)) else {
+                return Err(Error::UnrequestedOption(name.into_owned()));
+            };
+            if opt.is("blksize") {
+                // The server may pick a smaller block size, but never a larger one
+                let blksize: u16 = value(opt)?;
+                if blksize < MIN_BLKSIZE || blksize > value(req)? {
+                    return Err(Error::BadOptionValue(name.into_owned()));
+                }
+                negotiated.blksize = blksize as usize;
+            } else if opt.is("windowsize") {
+                // Same story for the window size
+                let windowsize: u16 = value(opt)?;
+                if windowsize == 0 || windowsize > value(req)? {
+                    return Err(Error::BadOptionValue(name.into_owned()));
+                }
+                negotiated.windowsize = windowsize as usize;
+            } else if opt.is("tsize") {
+                negotiated.tsize = Some(value(opt)?);
+            } else if opt.is("timeout") {
+                // The server has to use exactly the timeout we asked for
+                let timeout: u8 = value(opt)?;
+                if timeout != value::<u8>(req)? {
+                    return Err(Error::BadOptionValue(name.into_owned()));
+                }
+                negotiated.timeout = Some(Duration::from_secs(timeout as u64));
+            }
+        }
+        Ok(negotiated)
+    }
+}
+
+fn option<V: ToString>(name: &str, value: V) -> TransferOption {
+    TransferOption::new(name, value.to_string()).expect("Option strings never contain nulls")
+}
+
+fn value<T: FromStr>(opt: &TransferOption) -> Result<T, Error> {
+    opt.value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::BadOptionValue(opt.name.to_string_lossy().into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blksize_out_of_range() {
+        let options = Options {
+            blksize: Some(4),
+            ..Default::default()
+        };
+        assert!(matches!(
+            options.to_request(Some(0)),
+            Err(Error::BadOptionValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_blksize_smaller() {
+        let requested = [option("blksize", 1428)];
+        let negotiated = Options::negotiate(&requested, &[option("BLKSIZE", 1024)]).unwrap();
+        assert_eq!(negotiated.blksize, 1024);
+    }
+
+    #[test]
+    fn test_blksize_larger() {
+        let requested = [option("blksize", 1024)];
+        assert!(matches!(
+            Options::negotiate(&requested, &[option("blksize", 1428)]),
+            Err(Error::BadOptionValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_windowsize() {
+        let requested = [option("blksize", 1024), option("windowsize", 16)];
+        let negotiated = Options::negotiate(&requested, &[option("windowsize", 8)]).unwrap();
+        assert_eq!(negotiated.windowsize, 8);
+        assert_eq!(negotiated.blksize, BLKSIZE);
+    }
+
+    #[test]
+    fn test_tsize() {
+        let options = Options {
+            tsize: true,
+            ..Default::default()
+        };
+        let requested = options.to_request(Some(1234)).unwrap();
+        assert_eq!(requested, [option("tsize", 1234)]);
+        let negotiated = Options::negotiate(&requested, &[option("tsize", 1234)]).unwrap();
+        assert_eq!(negotiated.tsize, Some(1234));
+    }
+
+    #[test]
+    fn test_timeout_changed() {
+        let requested = [option("timeout", 2)];
+        assert!(matches!(
+            Options::negotiate(&requested, &[option("timeout", 5)]),
+            Err(Error::BadOptionValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_unrequested() {
+        assert!(matches!(
+            Options::negotiate(&[], &[option("blksize", 512)]),
+            Err(Error::UnrequestedOption(_))
+        ));
+    }
+}
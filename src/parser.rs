@@ -1,13 +1,11 @@
 //! Parser and serialization of the TFTP [`Packet`]
+//!
+//! [`PacketRef`] borrows its contents from the datagram it was parsed from and serializes into a
+//! caller-supplied buffer, so it's available without `std`
 
-use byte_strings::c_str;
-use std::{
-    ffi::{
-        CStr,
-        CString,
-    },
-    fmt::Display,
-};
+use core::fmt::Display;
+#[cfg(feature = "std")]
+use std::ffi::CString;
 use thiserror::Error;
 
 #[repr(u16)]
@@ -25,7 +23,7 @@ pub enum ErrorCode {
 }
 
 impl Display for ErrorCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             ErrorCode::Unspec => write!(f, "Not defined, see error message"),
             ErrorCode::NoFile => write!(f, "File not found"),
@@ -57,15 +55,16 @@ impl ErrorCode {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub enum RequestMode {
+    #[default]
     Octet,
     NetAscii,
     Mail,
 }
 
 impl Display for RequestMode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             RequestMode::Octet => write!(f, "octet"),
             RequestMode::NetAscii => write!(f, "netascii"),
@@ -75,105 +74,221 @@ impl Display for RequestMode {
 }
 
 impl RequestMode {
-    fn from_cstr(str: &CStr) -> Result<Self, Error> {
-        Ok(
-            match str
-                .to_str()
-                .map_err(|_| Error::BadString)?
-                .to_ascii_lowercase()
-                .as_str()
-            {
-                "octet" => Self::Octet,
-                "netascii" => Self::NetAscii,
-                "mail" => Self::Mail,
-                _ => return Err(Error::BadString),
-            },
-        )
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        // Modes are case-insensitive
+        if bytes.eq_ignore_ascii_case(b"octet") {
+            Ok(Self::Octet)
+        } else if bytes.eq_ignore_ascii_case(b"netascii") {
+            Ok(Self::NetAscii)
+        } else if bytes.eq_ignore_ascii_case(b"mail") {
+            Ok(Self::Mail)
+        } else {
+            Err(Error::BadString)
+        }
     }
 
-    fn into_cstr(self) -> &'static CStr {
+    fn as_bytes(self) -> &'static [u8] {
         match self {
-            RequestMode::Octet => c_str!("octet"),
-            RequestMode::NetAscii => c_str!("netascii"),
-            RequestMode::Mail => c_str!("mail"),
+            RequestMode::Octet => b"octet",
+            RequestMode::NetAscii => b"netascii",
+            RequestMode::Mail => b"mail",
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Packet {
+/// A list of options as specified in [RFC 2347](https://datatracker.ietf.org/doc/html/rfc2347),
+/// borrowed in its serialized form of consecutive null-terminated name/value pairs
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RawOptions<'a>(&'a [u8]);
+
+impl<'a> RawOptions<'a> {
+    /// Wrap serialized options, failing if they're malformed
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        // Every string is null-terminated, so the last byte must be a null
+        if bytes.last().is_some_and(|b| *b != 0) {
+            return Err(Error::BadString);
+        }
+        let options = Self(bytes);
+        let mut strings = options.strings();
+        while let Some(name) = strings.next() {
+            strings.next().ok_or(Error::BadOption)?;
+            if name.is_empty() {
+                return Err(Error::BadOption);
+            }
+        }
+        Ok(options)
+    }
+
+    /// The options as they're serialized
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Iterate over the name/value pairs, without their null terminators
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        let mut strings = self.strings();
+        core::iter::from_fn(move || Some((strings.next()?, strings.next()?)))
+    }
+
+    fn strings(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.0
+            .split_last()
+            .into_iter()
+            .flat_map(|(_, rest)| rest.split(|b| *b == 0))
+    }
+}
+
+/// A [`Packet`] that borrows its contents, where strings are given without their null terminator
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PacketRef<'a> {
     ReadRequest {
-        filename: CString,
+        filename: &'a [u8],
         mode: RequestMode,
+        options: RawOptions<'a>,
     },
     WriteRequest {
-        filename: CString,
+        filename: &'a [u8],
         mode: RequestMode,
+        options: RawOptions<'a>,
     },
     Data {
         block_n: u16,
-        data: Vec<u8>,
+        data: &'a [u8],
     },
     Acknowledgment {
         block_n: u16,
     },
     Error {
         code: ErrorCode,
-        msg: CString,
+        msg: &'a [u8],
+    },
+    OptionAcknowledgment {
+        options: RawOptions<'a>,
     },
 }
 
-impl Display for Packet {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Packet::ReadRequest { filename, mode } => {
-                write!(f, "RRQ {} {mode}", filename.to_str().unwrap())
-            }
-            Packet::WriteRequest { filename, mode } => {
-                write!(f, "WRQ {} {mode}", filename.to_str().unwrap())
-            }
-            Packet::Data { block_n, data: _ } => write!(f, "DATA block:{block_n}"),
-            Packet::Acknowledgment { block_n } => write!(f, "ACK block:{block_n}"),
-            Packet::Error { code, msg } => {
-                write!(f, "ERROR code:{code} msg:{}", msg.to_str().unwrap())
-            }
+/// Parse the body of a RRQ or WRQ into its filename, mode, and options
+fn parse_request(body: &[u8]) -> Result<(&[u8], RequestMode, RawOptions<'_>), Error> {
+    // Smallest size after the opcode is 7 bytes
+    // 2 bytes for 1 char filename and 5 bytes for "mail" mode
+    if body.len() < 7 {
+        return Err(Error::Incomplete(body.len()));
+    }
+    // Every string in the body is null-terminated, so the last byte must be a null
+    if body.last() != Some(&0) {
+        return Err(Error::BadString);
+    }
+    let mut strings = body.splitn(3, |b| *b == 0);
+    let filename = strings.next().ok_or(Error::Incomplete(0))?;
+    let mode = strings.next().ok_or(Error::Incomplete(0))?;
+    // Anything after the mode are options as per RFC 2347
+    let options = strings.next().ok_or(Error::Incomplete(0))?;
+    Ok((
+        filename,
+        RequestMode::from_bytes(mode)?,
+        RawOptions::new(options)?,
+    ))
+}
+
+/// Copies strings and integers into a buffer we've already checked is big enough
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn put_u16(&mut self, v: u16) {
+        self.put(&v.to_be_bytes());
+    }
+
+    /// Strings are null-terminated, so they can't contain nulls themselves
+    fn put_str(&mut self, str: &[u8]) -> Result<(), Error> {
+        if str.contains(&0) {
+            return Err(Error::BadString);
         }
+        self.put(str);
+        self.put(&[0]);
+        Ok(())
     }
 }
 
-impl Packet {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![];
+impl<'a> PacketRef<'a> {
+    /// The number of bytes the packet takes up once it's serialized
+    pub fn encoded_len(&self) -> usize {
+        2 + match self {
+            PacketRef::ReadRequest {
+                filename,
+                mode,
+                options,
+            }
+            | PacketRef::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => filename.len() + 1 + mode.as_bytes().len() + 1 + options.as_bytes().len(),
+            PacketRef::Data { data, .. } => 2 + data.len(),
+            PacketRef::Acknowledgment { .. } => 2,
+            PacketRef::Error { msg, .. } => 2 + msg.len() + 1,
+            PacketRef::OptionAcknowledgment { options } => options.as_bytes().len(),
+        }
+    }
+
+    /// Serialize the packet into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len));
+        }
+        let mut w = Writer { buf, pos: 0 };
         match self {
-            Packet::ReadRequest { filename, mode } => {
-                buf.extend_from_slice(&1u16.to_be_bytes());
-                buf.extend_from_slice(filename.to_bytes_with_nul());
-                buf.extend_from_slice(mode.into_cstr().to_bytes_with_nul());
+            PacketRef::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                w.put_u16(1);
+                w.put_str(filename)?;
+                w.put_str(mode.as_bytes())?;
+                w.put(options.as_bytes());
             }
-            Packet::WriteRequest { filename, mode } => {
-                buf.extend_from_slice(&2u16.to_be_bytes());
-                buf.extend_from_slice(filename.to_bytes_with_nul());
-                buf.extend_from_slice(mode.into_cstr().to_bytes_with_nul());
+            PacketRef::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                w.put_u16(2);
+                w.put_str(filename)?;
+                w.put_str(mode.as_bytes())?;
+                w.put(options.as_bytes());
             }
-            Packet::Data { block_n, data } => {
-                buf.extend_from_slice(&3u16.to_be_bytes());
-                buf.extend_from_slice(&block_n.to_be_bytes());
-                buf.extend_from_slice(data);
+            PacketRef::Data { block_n, data } => {
+                w.put_u16(3);
+                w.put_u16(*block_n);
+                w.put(data);
             }
-            Packet::Acknowledgment { block_n } => {
-                buf.extend_from_slice(&4u16.to_be_bytes());
-                buf.extend_from_slice(&block_n.to_be_bytes());
+            PacketRef::Acknowledgment { block_n } => {
+                w.put_u16(4);
+                w.put_u16(*block_n);
             }
-            Packet::Error { code, msg } => {
-                buf.extend_from_slice(&5u16.to_be_bytes());
-                buf.extend_from_slice(&(*code as u16).to_be_bytes());
-                buf.extend_from_slice(msg.as_bytes_with_nul());
+            PacketRef::Error { code, msg } => {
+                w.put_u16(5);
+                w.put_u16(*code as u16);
+                w.put_str(msg)?;
+            }
+            PacketRef::OptionAcknowledgment { options } => {
+                w.put_u16(6);
+                w.put(options.as_bytes());
             }
         }
-        buf
+        Ok(w.pos)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, Error> {
         if bytes.len() < 4 {
             // Check against the smallest payload size (ACK)
             return Err(Error::Incomplete(bytes.len()));
@@ -184,40 +299,22 @@ impl Packet {
         match opcode {
             // RRQ
             1 => {
-                // Smallest size after the opcode is 7 bytes
-                // 2 bytes for 1 char filename and 5 bytes for "mail" mode
-                if body.len() < 7 {
-                    Err(Error::Incomplete(body.len()))
-                } else {
-                    // The rest should have exactly two null bytes, one for each string
-                    let mut iter = body.splitn(3, |x| *x == 0);
-                    let filename = iter.next().ok_or(Error::Incomplete(0))?;
-                    let mode = iter.next().ok_or(Error::Incomplete(0))?;
-                    Ok(Packet::ReadRequest {
-                        filename: CString::new(filename).map_err(|_| Error::BadString)?,
-                        mode: RequestMode::from_cstr(
-                            &CString::new(mode).map_err(|_| Error::BadString)?,
-                        )?,
-                    })
-                }
+                let (filename, mode, options) = parse_request(body)?;
+                Ok(PacketRef::ReadRequest {
+                    filename,
+                    mode,
+                    options,
+                })
             }
             // WRQ
             2 => {
                 // Same story as RRQ, but different discriminant
-                if body.len() < 7 {
-                    Err(Error::Incomplete(body.len()))
-                } else {
-                    // The rest should have exactly two null bytes, one for each string
-                    let mut iter = body.splitn(3, |x| *x == 0);
-                    let filename = iter.next().ok_or(Error::Incomplete(0))?;
-                    let mode = iter.next().ok_or(Error::Incomplete(0))?;
-                    Ok(Packet::WriteRequest {
-                        filename: CString::new(filename).map_err(|_| Error::BadString)?,
-                        mode: RequestMode::from_cstr(
-                            &CString::new(mode).map_err(|_| Error::BadString)?,
-                        )?,
-                    })
-                }
+                let (filename, mode, options) = parse_request(body)?;
+                Ok(PacketRef::WriteRequest {
+                    filename,
+                    mode,
+                    options,
+                })
             }
             // DATA
             3 => {
@@ -226,15 +323,17 @@ impl Packet {
                     Err(Error::Incomplete(body.len()))
                 } else {
                     let block_n = u16::from_be_bytes(body[..2].try_into().unwrap());
-                    let data = body[2..].to_vec();
-                    Ok(Packet::Data { block_n, data })
+                    Ok(PacketRef::Data {
+                        block_n,
+                        data: &body[2..],
+                    })
                 }
             }
             // ACK
             4 => {
                 // We've already checked length for this smallest payload
                 let block_n = u16::from_be_bytes(body[..2].try_into().unwrap());
-                Ok(Packet::Acknowledgment { block_n })
+                Ok(PacketRef::Acknowledgment { block_n })
             }
             // ERROR
             5 => {
@@ -246,21 +345,246 @@ impl Packet {
                     let code =
                         ErrorCode::from_u16(u16::from_be_bytes(body[0..2].try_into().unwrap()))?;
                     // The rest should have exactly one null byte at the end for the string
-                    if *body[2..].last().unwrap() != 0 {
-                        Err(Error::BadString)
-                    } else {
-                        let msg = CString::new(&body[2..(body.len() - 1)])
-                            .map_err(|_| Error::BadString)?;
-                        Ok(Packet::Error { code, msg })
+                    match body[2..].split_last() {
+                        Some((0, msg)) if !msg.contains(&0) => Ok(PacketRef::Error { code, msg }),
+                        _ => Err(Error::BadString),
                     }
                 }
             }
+            // OACK
+            6 => Ok(PacketRef::OptionAcknowledgment {
+                options: RawOptions::new(body)?,
+            }),
             _ => Err(Error::BadOpcode(opcode)),
         }
     }
 }
 
-#[derive(Debug, Error)]
+/// A single name/value option as specified in [RFC 2347](https://datatracker.ietf.org/doc/html/rfc2347)
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferOption {
+    pub name: CString,
+    pub value: CString,
+}
+
+#[cfg(feature = "std")]
+impl Display for TransferOption {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}={}",
+            self.name.to_string_lossy(),
+            self.value.to_string_lossy()
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl TransferOption {
+    /// Create a new option, failing if either string contains a null byte
+    pub fn new<N: Into<Vec<u8>>, V: Into<Vec<u8>>>(name: N, value: V) -> Result<Self, Error> {
+        Ok(Self {
+            name: CString::new(name).map_err(|_| Error::BadString)?,
+            value: CString::new(value).map_err(|_| Error::BadString)?,
+        })
+    }
+
+    /// Option names are case-insensitive, so compare them as such
+    pub fn is(&self, name: &str) -> bool {
+        self.name.to_bytes().eq_ignore_ascii_case(name.as_bytes())
+    }
+}
+
+/// Serialize a list of options as consecutive null-terminated name/value pairs
+#[cfg(feature = "std")]
+fn options_to_bytes(options: &[TransferOption], buf: &mut Vec<u8>) {
+    for opt in options {
+        buf.extend_from_slice(opt.name.as_bytes_with_nul());
+        buf.extend_from_slice(opt.value.as_bytes_with_nul());
+    }
+}
+
+/// Copy borrowed options into owned ones
+#[cfg(feature = "std")]
+fn options_to_owned(options: RawOptions) -> Vec<TransferOption> {
+    options
+        .iter()
+        .map(|(name, value)| {
+            TransferOption::new(name, value).expect("Parsed strings never contain nulls")
+        })
+        .collect()
+}
+
+#[cfg(feature = "std")]
+fn cstring(str: &[u8]) -> CString {
+    CString::new(str).expect("Parsed strings never contain nulls")
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    ReadRequest {
+        filename: CString,
+        mode: RequestMode,
+        options: Vec<TransferOption>,
+    },
+    WriteRequest {
+        filename: CString,
+        mode: RequestMode,
+        options: Vec<TransferOption>,
+    },
+    Data {
+        block_n: u16,
+        data: Vec<u8>,
+    },
+    Acknowledgment {
+        block_n: u16,
+    },
+    Error {
+        code: ErrorCode,
+        msg: CString,
+    },
+    OptionAcknowledgment {
+        options: Vec<TransferOption>,
+    },
+}
+
+/// Write out options as a space-separated list, prefixed by a space if there are any
+#[cfg(feature = "std")]
+fn fmt_options(f: &mut core::fmt::Formatter<'_>, options: &[TransferOption]) -> core::fmt::Result {
+    for opt in options {
+        write!(f, " {opt}")?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+impl Display for Packet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                write!(f, "RRQ {} {mode}", filename.to_str().unwrap())?;
+                fmt_options(f, options)
+            }
+            Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                write!(f, "WRQ {} {mode}", filename.to_str().unwrap())?;
+                fmt_options(f, options)
+            }
+            Packet::Data { block_n, data: _ } => write!(f, "DATA block:{block_n}"),
+            Packet::Acknowledgment { block_n } => write!(f, "ACK block:{block_n}"),
+            Packet::Error { code, msg } => {
+                write!(f, "ERROR code:{code} msg:{}", msg.to_str().unwrap())
+            }
+            Packet::OptionAcknowledgment { options } => {
+                write!(f, "OACK")?;
+                fmt_options(f, options)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<PacketRef<'_>> for Packet {
+    fn from(pkt: PacketRef<'_>) -> Self {
+        match pkt {
+            PacketRef::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => Packet::ReadRequest {
+                filename: cstring(filename),
+                mode,
+                options: options_to_owned(options),
+            },
+            PacketRef::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => Packet::WriteRequest {
+                filename: cstring(filename),
+                mode,
+                options: options_to_owned(options),
+            },
+            PacketRef::Data { block_n, data } => Packet::Data {
+                block_n,
+                data: data.to_vec(),
+            },
+            PacketRef::Acknowledgment { block_n } => Packet::Acknowledgment { block_n },
+            PacketRef::Error { code, msg } => Packet::Error {
+                code,
+                msg: cstring(msg),
+            },
+            PacketRef::OptionAcknowledgment { options } => Packet::OptionAcknowledgment {
+                options: options_to_owned(options),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Packet::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                buf.extend_from_slice(&1u16.to_be_bytes());
+                buf.extend_from_slice(filename.to_bytes_with_nul());
+                buf.extend_from_slice(mode.as_bytes());
+                buf.push(0);
+                options_to_bytes(options, &mut buf);
+            }
+            Packet::WriteRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                buf.extend_from_slice(&2u16.to_be_bytes());
+                buf.extend_from_slice(filename.to_bytes_with_nul());
+                buf.extend_from_slice(mode.as_bytes());
+                buf.push(0);
+                options_to_bytes(options, &mut buf);
+            }
+            Packet::Data { block_n, data } => {
+                buf.extend_from_slice(&3u16.to_be_bytes());
+                buf.extend_from_slice(&block_n.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Packet::Acknowledgment { block_n } => {
+                buf.extend_from_slice(&4u16.to_be_bytes());
+                buf.extend_from_slice(&block_n.to_be_bytes());
+            }
+            Packet::Error { code, msg } => {
+                buf.extend_from_slice(&5u16.to_be_bytes());
+                buf.extend_from_slice(&(*code as u16).to_be_bytes());
+                buf.extend_from_slice(msg.as_bytes_with_nul());
+            }
+            Packet::OptionAcknowledgment { options } => {
+                buf.extend_from_slice(&6u16.to_be_bytes());
+                options_to_bytes(options, &mut buf);
+            }
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        PacketRef::from_bytes(bytes).map(Packet::from)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
 pub enum Error {
     #[error("Too few bytes recieved - `{0}`")]
     Incomplete(usize),
@@ -270,9 +594,13 @@ pub enum Error {
     BadString,
     #[error("Error code wasn't recognized - `{0}`")]
     BadErrorCode(u16),
+    #[error("Option list was malformed")]
+    BadOption,
+    #[error("Buffer too small, the packet needs `{0}` bytes")]
+    BufferTooSmall(usize),
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 pub mod tests {
     use super::*;
     use paste::paste;
@@ -294,12 +622,12 @@ pub mod tests {
         };
     }
 
-    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet}, "rrq_octet"}
-    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::NetAscii}, "rrq_netascii"}
-    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode:: Mail}, "rrq_mail"}
-    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet}, "wrq_octet"}
-    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::NetAscii}, "wrq_netascii"}
-    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode:: Mail}, "wrq_mail"}
+    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet, options: vec![]}, "rrq_octet"}
+    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::NetAscii, options: vec![]}, "rrq_netascii"}
+    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode:: Mail, options: vec![]}, "rrq_mail"}
+    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet, options: vec![]}, "wrq_octet"}
+    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::NetAscii, options: vec![]}, "wrq_netascii"}
+    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode:: Mail, options: vec![]}, "wrq_mail"}
     test_happy_packet! {Packet::Data {block_n: 42, data: vec![0xDE, 0xAD, 0xBE, 0xEF]}, "data"}
     test_happy_packet! {Packet::Data {block_n: 123, data: vec![]}, "data_empty"}
     test_happy_packet! {Packet::Acknowledgment { block_n: 42 }, "ack"}
@@ -313,4 +641,64 @@ pub mod tests {
     test_happy_packet! {Packet::Error { code: ErrorCode::BadUser, msg: CString::new("Msg").unwrap() }, "error_baduser"}
     test_happy_packet! {Packet::Error { code: ErrorCode::BadOpt, msg: CString::new("Msg").unwrap() }, "error_badopt"}
     test_happy_packet! {Packet::Error { code: ErrorCode::BadOpt, msg: CString::new("").unwrap() }, "error_empty"}
+    test_happy_packet! {Packet::ReadRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet, options: vec![TransferOption::new("foo", "bar").unwrap()]}, "rrq_option"}
+    test_happy_packet! {Packet::WriteRequest {filename:CString::new("foo").unwrap(), mode: RequestMode::Octet, options: vec![TransferOption::new("foo", "bar").unwrap(), TransferOption::new("baz", "").unwrap()]}, "wrq_options"}
+    test_happy_packet! {Packet::OptionAcknowledgment { options: vec![TransferOption::new("foo", "bar").unwrap()] }, "oack"}
+
+    #[test]
+    fn test_rrq_dangling_option() {
+        assert!(matches!(
+            Packet::from_bytes(b"\x00\x01foo\x00octet\x00bar\x00"),
+            Err(Error::BadOption)
+        ));
+    }
+
+    #[test]
+    fn test_option_name_case() {
+        assert!(TransferOption::new("BlkSize", "1024")
+            .unwrap()
+            .is("blksize"));
+    }
+
+    #[test]
+    fn test_packet_ref_round_trip() {
+        let options = RawOptions::new(b"blksize\x001024\x00tsize\x000\x00").unwrap();
+        let pkt = PacketRef::ReadRequest {
+            filename: b"foo",
+            mode: RequestMode::Octet,
+            options,
+        };
+        let mut buf = [0; 64];
+        let n = pkt.encode(&mut buf).unwrap();
+        assert_eq!(n, pkt.encoded_len());
+        assert_eq!(PacketRef::from_bytes(&buf[..n]).unwrap(), pkt);
+        // Serialized the same way as the owned packets
+        let owned = Packet::from(pkt);
+        assert_eq!(owned.to_bytes(), &buf[..n]);
+        assert_eq!(
+            options.iter().collect::<Vec<_>>(),
+            [(&b"blksize"[..], &b"1024"[..]), (b"tsize", b"0")]
+        );
+    }
+
+    #[test]
+    fn test_packet_ref_buffer_too_small() {
+        let pkt = PacketRef::Data {
+            block_n: 1,
+            data: &[0; 512],
+        };
+        assert!(matches!(
+            pkt.encode(&mut [0; 515]),
+            Err(Error::BufferTooSmall(516))
+        ));
+    }
+
+    #[test]
+    fn test_packet_ref_null_in_string() {
+        let pkt = PacketRef::Error {
+            code: ErrorCode::Unspec,
+            msg: b"foo\x00bar",
+        };
+        assert!(matches!(pkt.encode(&mut [0; 64]), Err(Error::BadString)));
+    }
 }
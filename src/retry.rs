@@ -0,0 +1,447 @@
+//! Policies for how long to wait for the server before retransmitting, and when to give up
+//!
+//! Every transfer gets its own policy, which hears about every timeout and every time the
+//! transfer makes progress. [`Exponential`] is what the functions that take a `timeout`,
+//! `max_timeout` and number of `retries` use, while [`Adaptive`] and [`Jacobson`] adjust to how
+//! quickly the server replies.
+
+use core::{
+    fmt::Debug,
+    time::Duration,
+};
+
+/// Decides how long to wait for the server, and when to give up
+pub trait RetryPolicy: Debug + Send {
+    /// How long to wait for the server to reply
+    fn timeout(&self) -> Duration;
+    /// The server replied and the transfer moved along, where `rtt` is how long the reply took
+    /// (if we know)
+    fn on_progress(&mut self, rtt: Option<Duration>);
+    /// Nothing arrived in time, so back off before retransmitting, returning `false` to give up
+    fn on_timeout(&mut self) -> bool;
+    /// The server agreed to retransmit every `timeout` as per
+    /// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349)
+    fn on_server_timeout(&mut self, _timeout: Duration) {}
+}
+
+/// Wait 1.5 times longer after every timeout, up to a limit, optionally with some jitter so
+/// clients that lose packets at the same time don't keep retransmitting at the same time
+///
+/// Unless it was seeded with [`with_seed`](Exponential::with_seed), every clone of a policy with
+/// jitter gets a fresh random seed, so the transfers sharing it don't end up in lockstep either.
+#[derive(Debug)]
+pub struct Exponential {
+    initial: Duration,
+    max: Duration,
+    retries: usize,
+    jitter: f32,
+    rng: u64,
+    // Whether the seed is random, in which case every clone gets a new one
+    random_seed: bool,
+    // The timeout without any jitter, which is what we back off from
+    backoff: Duration,
+    timeout: Duration,
+    remaining: usize,
+}
+
+impl Exponential {
+    /// Start by waiting `initial`, backing off up to `max`, and give up after `retries` timeouts
+    /// in a row
+    pub fn new(initial: Duration, max: Duration, retries: usize) -> Self {
+        Self {
+            initial,
+            max,
+            retries,
+            jitter: 0.0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            random_seed: false,
+            backoff: initial,
+            timeout: initial,
+            remaining: retries,
+        }
+    }
+
+    /// Randomly lengthen or shorten every timeout by up to `jitter` (at most 0.5, so we never
+    /// wait less than half as long) of it
+    pub fn with_jitter(mut self, jitter: f32) -> Self {
+        self.jitter = jitter.clamp(0.0, 0.5);
+        #[cfg(feature = "std")]
+        {
+            self = self.with_seed(random_seed());
+            self.random_seed = true;
+        }
+        self.timeout = self.jittered(self.backoff);
+        self
+    }
+
+    /// Seed the jitter, which is otherwise random with the `std` feature (and the same every
+    /// time without it)
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        self.rng = seed | 1;
+        self.random_seed = false;
+        self.timeout = self.jittered(self.backoff);
+        self
+    }
+
+    /// `timeout` randomly lengthened or shortened by the jitter
+    fn jittered(&mut self, timeout: Duration) -> Duration {
+        if self.jitter > 0.0 {
+            let factor = 1.0 + self.jitter * self.random();
+            timeout.mul_f32(factor).min(self.max)
+        } else {
+            timeout
+        }
+    }
+
+    /// A random number between -1 and 1
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+impl Clone for Exponential {
+    fn clone(&self) -> Self {
+        #[cfg(feature = "std")]
+        if self.random_seed {
+            let mut clone = Self {
+                rng: random_seed() | 1,
+                ..*self
+            };
+            clone.timeout = clone.jittered(clone.backoff);
+            return clone;
+        }
+        Self { ..*self }
+    }
+}
+
+#[cfg(feature = "std")]
+fn random_seed() -> u64 {
+    use std::hash::{
+        BuildHasher,
+        RandomState,
+    };
+    RandomState::new().hash_one(0u64)
+}
+
+impl RetryPolicy for Exponential {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn on_progress(&mut self, _rtt: Option<Duration>) {
+        self.remaining = self.retries;
+        self.backoff = self.initial;
+        self.timeout = self.jittered(self.initial);
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            return false;
+        }
+        self.backoff = (self.backoff + self.backoff / 2).min(self.max);
+        self.timeout = self.jittered(self.backoff);
+        true
+    }
+
+    fn on_server_timeout(&mut self, timeout: Duration) {
+        // Retransmit on the same interval as the server, backing off from there
+        self.initial = timeout;
+        self.max = self.max.max(timeout);
+        self.backoff = timeout;
+        self.timeout = timeout;
+    }
+}
+
+/// Always wait the same amount of time, and give up after a number of timeouts in a row
+#[derive(Debug, Clone)]
+pub struct Constant {
+    timeout: Duration,
+    retries: usize,
+    remaining: usize,
+}
+
+impl Constant {
+    /// Wait `timeout` every time, and give up after `retries` timeouts in a row
+    pub fn new(timeout: Duration, retries: usize) -> Self {
+        Self {
+            timeout,
+            retries,
+            remaining: retries,
+        }
+    }
+}
+
+impl RetryPolicy for Constant {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn on_progress(&mut self, _rtt: Option<Duration>) {
+        self.remaining = self.retries;
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        self.remaining > 0
+    }
+
+    fn on_server_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+/// Wait a multiple of how long the server took to reply last time, doubling after every timeout
+///
+/// Until we've heard back from the server, this waits the initial timeout
+#[derive(Debug, Clone)]
+pub struct Adaptive {
+    min: Duration,
+    max: Duration,
+    retries: usize,
+    multiplier: u32,
+    base: Duration,
+    timeout: Duration,
+    remaining: usize,
+}
+
+impl Adaptive {
+    /// Start by waiting `initial`, never wait longer than `max`, and give up after `retries`
+    /// timeouts in a row
+    pub fn new(initial: Duration, max: Duration, retries: usize) -> Self {
+        Self {
+            min: Duration::from_millis(10),
+            max,
+            retries,
+            multiplier: 2,
+            base: initial,
+            timeout: initial,
+            remaining: retries,
+        }
+    }
+
+    /// Never wait less than `min`, which is 10 ms by default
+    pub fn with_min(mut self, min: Duration) -> Self {
+        self.min = min;
+        self
+    }
+
+    /// Wait `multiplier` times the round-trip time, which is 2 by default
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier.max(1);
+        self
+    }
+}
+
+impl RetryPolicy for Adaptive {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn on_progress(&mut self, rtt: Option<Duration>) {
+        self.remaining = self.retries;
+        if let Some(rtt) = rtt {
+            self.base = (rtt * self.multiplier).clamp(self.min, self.max);
+        }
+        self.timeout = self.base;
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            return false;
+        }
+        self.timeout = (self.timeout * 2).min(self.max);
+        true
+    }
+}
+
+/// Estimate the round-trip time and how much it varies as per
+/// [RFC 6298](https://datatracker.ietf.org/doc/html/rfc6298) (Jacobson's algorithm), and wait long
+/// enough for nearly every reply, doubling after every timeout
+///
+/// Replies to packets that had to be sent again don't count, as there's no telling which copy
+/// they answer (Karn's algorithm). Until we've heard back from the server, and after a timeout
+/// until we get a new measurement, this keeps waiting as long as it did last.
+#[derive(Debug, Clone)]
+pub struct Jacobson {
+    min: Duration,
+    max: Duration,
+    retries: usize,
+    // The smoothed round-trip time and its variation, once we've measured it
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    timeout: Duration,
+    remaining: usize,
+}
+
+impl Jacobson {
+    /// Start by waiting `initial`, never wait longer than `max`, and give up after `retries`
+    /// timeouts in a row
+    pub fn new(initial: Duration, max: Duration, retries: usize) -> Self {
+        Self {
+            min: Duration::from_millis(10),
+            max,
+            retries,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            timeout: initial,
+            remaining: retries,
+        }
+    }
+
+    /// Never wait less than `min`, which is 10 ms by default
+    pub fn with_min(mut self, min: Duration) -> Self {
+        self.min = min;
+        self
+    }
+
+    /// The smoothed round-trip time, if we've measured it yet
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How much the round-trip time varies
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+impl RetryPolicy for Jacobson {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn on_progress(&mut self, rtt: Option<Duration>) {
+        self.remaining = self.retries;
+        let Some(rtt) = rtt else {
+            return;
+        };
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let error = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + error) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.timeout = (srtt + self.rttvar * 4).clamp(self.min, self.max);
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            return false;
+        }
+        self.timeout = (self.timeout * 2).min(self.max);
+        true
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential() {
+        let mut policy = Exponential::new(Duration::from_secs(1), Duration::from_secs(2), 3);
+        assert!(policy.on_timeout());
+        assert_eq!(policy.timeout(), Duration::from_millis(1500));
+        assert!(policy.on_timeout());
+        assert_eq!(policy.timeout(), Duration::from_secs(2));
+        assert!(!policy.on_timeout());
+        policy.on_progress(None);
+        assert_eq!(policy.timeout(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_exponential_jitter() {
+        let mut policy = Exponential::new(Duration::from_secs(1), Duration::from_secs(60), 100)
+            .with_jitter(0.5)
+            .with_seed(42);
+        let mut timeouts = vec![policy.timeout()];
+        let mut backoff = Duration::from_secs(1);
+        // Even the first timeout is jittered
+        assert_ne!(policy.timeout(), backoff);
+        for _ in 0..8 {
+            policy.on_timeout();
+            backoff = (backoff + backoff / 2).min(Duration::from_secs(60));
+            // Stays within the jitter of the backoff, which itself doesn't drift
+            assert!(policy.timeout() >= backoff / 2 && policy.timeout() <= backoff * 3 / 2);
+            timeouts.push(policy.timeout());
+        }
+        assert!(timeouts.windows(2).any(|w| w[0] * 3 / 2 != w[1]));
+        policy.on_progress(None);
+        assert_ne!(policy.timeout(), Duration::from_secs(1));
+        // Too much jitter could leave us not waiting at all
+        let policy = Exponential::new(Duration::from_secs(1), Duration::from_secs(60), 100)
+            .with_jitter(1.0)
+            .with_seed(42);
+        assert_eq!(policy.jitter, 0.5);
+    }
+
+    #[test]
+    fn test_exponential_jitter_clone() {
+        let timeouts = |mut policy: Exponential| {
+            let mut timeouts = vec![policy.timeout()];
+            for _ in 0..8 {
+                policy.on_timeout();
+                timeouts.push(policy.timeout());
+            }
+            timeouts
+        };
+        let policy =
+            Exponential::new(Duration::from_secs(1), Duration::from_secs(60), 100).with_jitter(0.5);
+        // Every copy backs off differently
+        assert_ne!(timeouts(policy.clone()), timeouts(policy.clone()));
+        // Unless we asked for the same seed
+        let policy = policy.with_seed(42);
+        assert_eq!(timeouts(policy.clone()), timeouts(policy.clone()));
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let mut policy = Adaptive::new(Duration::from_secs(1), Duration::from_secs(4), 3);
+        assert_eq!(policy.timeout(), Duration::from_secs(1));
+        policy.on_progress(Some(Duration::from_millis(30)));
+        assert_eq!(policy.timeout(), Duration::from_millis(60));
+        assert!(policy.on_timeout());
+        assert_eq!(policy.timeout(), Duration::from_millis(120));
+        // Without a new measurement, we go back to the last one
+        policy.on_progress(None);
+        assert_eq!(policy.timeout(), Duration::from_millis(60));
+        policy.on_progress(Some(Duration::from_secs(10)));
+        assert_eq!(policy.timeout(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_jacobson() {
+        let mut policy = Jacobson::new(Duration::from_secs(1), Duration::from_secs(4), 3);
+        assert_eq!(policy.timeout(), Duration::from_secs(1));
+        policy.on_progress(Some(Duration::from_millis(100)));
+        assert_eq!(policy.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(policy.timeout(), Duration::from_millis(300));
+        // A steady round-trip time narrows the margin
+        policy.on_progress(Some(Duration::from_millis(100)));
+        assert_eq!(policy.rttvar(), Duration::from_micros(37500));
+        assert_eq!(policy.timeout(), Duration::from_millis(250));
+        // After a timeout, we keep waiting longer until there's a new measurement
+        assert!(policy.on_timeout());
+        assert_eq!(policy.timeout(), Duration::from_millis(500));
+        policy.on_progress(None);
+        assert_eq!(policy.timeout(), Duration::from_millis(500));
+        // A slow reply moves the average a little, and the margin a lot
+        policy.on_progress(Some(Duration::from_millis(900)));
+        assert_eq!(policy.srtt(), Some(Duration::from_millis(200)));
+        assert_eq!(policy.rttvar(), Duration::from_micros(228125));
+        assert_eq!(policy.timeout(), Duration::from_micros(1112500));
+    }
+}
@@ -0,0 +1,197 @@
+//! Asynchonous implementation of the TFTP client,
+//! using [`tokio`](https://tokio.rs) for networking and timers
+//!
+//! This is the same client as [`asynchronous`](crate::asynchronous), with
+//! [`tokio::net::UdpSocket`] as the [`Transport`] and streaming through tokio's `AsyncRead` and
+//! `AsyncWrite`
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use ::tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+    },
+    net::UdpSocket,
+    time,
+};
+
+pub use crate::asynchronous::{
+    download,
+    download_with_options,
+    query_size,
+    upload,
+    upload_with_options,
+    Client,
+    Transport,
+};
+use crate::{
+    asynchronous::{
+        download_into,
+        upload_inner,
+        Sink,
+        Source,
+    },
+    transfer::exponential,
+    ClientBuilder,
+    Error,
+    Options,
+};
+
+impl ClientBuilder {
+    /// Check the configuration, and bind the socket for an async client running on tokio
+    ///
+    /// To stream through tokio's `AsyncRead` and `AsyncWrite`, use
+    /// [`get_to_tokio`](Client::get_to_tokio) and [`put_from_tokio`](Client::put_from_tokio).
+    pub async fn build_tokio(self) -> Result<Client<UdpSocket>, Error> {
+        let socket = UdpSocket::bind(self.bind_addr())
+            .await
+            .map_err(Error::SocketIo)?;
+        self.build_with(socket)
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<usize>> {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        time::sleep(duration)
+    }
+}
+
+impl<S: Transport> Client<S> {
+    /// Download a file, writing each block to tokio's `writer` as soon as it arrives
+    ///
+    /// Returns the number of bytes written
+    pub async fn get_to_tokio<T: AsRef<str> + std::fmt::Display, W: AsyncWrite + Unpin>(
+        &self,
+        filename: T,
+        writer: W,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        download_into(
+            filename,
+            &mut WriteSink(writer),
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await
+    }
+
+    /// Upload a file, reading the data from tokio's `reader` one block at a time until it runs
+    /// out
+    ///
+    /// Returns the number of bytes read
+    pub async fn put_from_tokio<T: AsRef<str> + std::fmt::Display, R: AsyncRead + Unpin>(
+        &self,
+        filename: T,
+        reader: R,
+    ) -> Result<u64, Error> {
+        let s = &self.settings;
+        upload_inner(
+            filename,
+            ReadSource(reader),
+            None,
+            &self.socket,
+            s.server,
+            &|| s.policy(),
+            s.observer.as_ref(),
+            &s.options,
+        )
+        .await
+    }
+}
+
+/// Download a file via tftp, negotiating `options` with the server and writing each block to
+/// `writer` as soon as it arrives
+///
+/// Returns the number of bytes written
+#[allow(clippy::too_many_arguments)]
+pub async fn download_to<T: AsRef<str> + std::fmt::Display, S: Transport, W: AsyncWrite + Unpin>(
+    filename: T,
+    writer: W,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    download_into(
+        filename,
+        &mut WriteSink(writer),
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+    .await
+}
+
+struct WriteSink<W>(W);
+
+impl<W: AsyncWrite + Unpin> Sink for WriteSink<W> {
+    async fn put(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(data).await
+    }
+}
+
+/// Upload a file via tftp, negotiating `options` with the server and reading the data from
+/// `reader` one block at a time until it runs out
+///
+/// As the size isn't known up front, the `tsize` option isn't sent. Returns the number of bytes
+/// read.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_from<T: AsRef<str> + std::fmt::Display, S: Transport, R: AsyncRead + Unpin>(
+    filename: T,
+    reader: R,
+    socket: &S,
+    server: SocketAddr,
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+    options: &Options,
+) -> Result<u64, Error> {
+    upload_inner(
+        filename,
+        ReadSource(reader),
+        None,
+        socket,
+        server,
+        &exponential(timeout, max_timeout, retries),
+        None,
+        options,
+    )
+    .await
+}
+
+struct ReadSource<R>(R);
+
+impl<R: AsyncRead + Unpin> Source for ReadSource<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
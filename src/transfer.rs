@@ -0,0 +1,2092 @@
+//! Runtime-agnostic transfer state machines
+//!
+//! These don't touch the network (or the clock) themselves, which makes it possible to drive a
+//! transfer from any event loop, passing in the current time with every call. Send out everything
+//! [`Transfer::poll_transmit`] hands back, then wait up to [`Transfer::timeout`] for a datagram.
+//! Pass whatever arrives to [`Transfer::on_datagram`], or call [`Transfer::on_timeout`] if nothing
+//! did, and repeat. If anything goes wrong along the way, [`Transfer::abort`] lets the server know
+//! we're giving up.
+//!
+//! To keep an eye on a transfer as it goes, hand it an [`Observer`] that gets told about every
+//! [`Event`].
+
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    net::SocketAddr,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use tracing::debug;
+
+use crate::{
+    netascii,
+    options::Negotiated,
+    parser::{
+        ErrorCode,
+        Packet,
+        RequestMode,
+        TransferOption,
+    },
+    retry::{
+        Exponential,
+        RetryPolicy,
+    },
+    Error,
+    Options,
+    Rollover,
+};
+
+/// A datagram a transfer wants sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit {
+    /// Where to send it, which is the server's transfer ID once it has picked one
+    pub destination: SocketAddr,
+    /// The encoded packet
+    pub datagram: Vec<u8>,
+}
+
+/// Something that happened during a transfer, for progress reporting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The RRQ or WRQ went out, which happens again if the server refuses our options
+    RequestSent,
+    /// The server acknowledged our options, and these are the ones the transfer goes ahead with
+    Negotiated {
+        blksize: usize,
+        windowsize: usize,
+        /// The size of the file, if the server told us (or acknowledged ours)
+        tsize: Option<u64>,
+        /// How often the server retransmits, if it agreed to our timeout
+        timeout: Option<Duration>,
+    },
+    /// A block made it across, which is when it arrives for a download and when the server acks
+    /// it for an upload
+    Block {
+        /// The block's position in the file, counting from 1 (unlike block numbers, this doesn't
+        /// roll over)
+        block: u64,
+        /// How many bytes of the file have made it so far, as they're sent over the wire (which
+        /// is before netascii is translated back)
+        bytes: u64,
+        /// The size of the file, if we know it
+        total: Option<u64>,
+    },
+    /// We sent a packet again, as the server didn't respond in time
+    Retransmit,
+    /// We gave up waiting on the server, which is followed by a retransmit if we have retries
+    /// left
+    Timeout,
+    /// The whole file made it across
+    Complete {
+        /// The size of the file as it went over the wire
+        bytes: u64,
+    },
+}
+
+/// Gets told about every [`Event`] of a transfer, for things like progress bars
+///
+/// This is implemented for closures, so `|event: &Event| println!("{event:?}")` will do.
+pub trait Observer: Send + Sync {
+    /// Called as soon as `event` happens
+    fn on_event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Observer for F {
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl std::fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// The interface shared by [`ReadTransfer`] and [`WriteTransfer`] for moving datagrams in and out
+pub trait Transfer {
+    /// Process a datagram that arrived from `source`
+    fn on_datagram(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<(), Error>;
+    /// Let the transfer know that [`timeout`](Transfer::timeout) passed without a datagram
+    /// arriving, which retransmits as the [`RetryPolicy`] sees fit or gives up with
+    /// [`Error::Timeout`]
+    fn on_timeout(&mut self, now: Instant) -> Result<(), Error>;
+    /// The next datagram to send, if there is one
+    fn poll_transmit(&mut self, now: Instant) -> Option<Transmit>;
+    /// How long to wait for the next datagram, or `None` if we're not waiting on the server
+    fn timeout(&self, now: Instant) -> Option<Duration>;
+    /// The largest datagram we expect to receive, for sizing receive buffers
+    fn max_datagram_len(&self) -> usize;
+    /// Give up on the transfer because of `error`, queueing an ERROR packet so the server doesn't
+    /// keep retransmitting until it times out too
+    fn abort(&mut self, error: &Error);
+}
+
+/// The connection to the server, which keeps track of retries and queues outgoing datagrams
+#[derive(Debug)]
+struct Link {
+    server: SocketAddr,
+    // Keep track of the original address in case we need to send the request again
+    initial_server: SocketAddr,
+    // Whether the server's transfer ID is locked in, which happens with its first reply
+    tid_locked: bool,
+    strict_host: bool,
+    aborted: bool,
+    policy: Box<dyn RetryPolicy>,
+    // The whole transfer has to be done by `deadline` after it started, if there is one
+    deadline: Option<Duration>,
+    started: Option<Instant>,
+    // The time as of the call we're handling, and when we last sent something to the server
+    now: Option<Instant>,
+    last_sent: Option<Instant>,
+    // Whether we've sent anything again since we last made progress, in which case we can't tell
+    // which copy the server replied to (so the round-trip time is anyone's guess)
+    retransmitted: bool,
+    // Whether the request is waiting in the outbox, so we can tell the observer when it goes out
+    request_pending: bool,
+    observer: Option<Arc<dyn Observer>>,
+    outbox: VecDeque<Transmit>,
+}
+
+impl Link {
+    fn new(server: SocketAddr, policy: Box<dyn RetryPolicy>, options: &Options) -> Self {
+        Self {
+            server,
+            initial_server: server,
+            tid_locked: false,
+            strict_host: options.strict_host,
+            aborted: false,
+            policy,
+            deadline: options.deadline,
+            started: None,
+            now: None,
+            last_sent: None,
+            retransmitted: false,
+            request_pending: true,
+            observer: None,
+            outbox: VecDeque::new(),
+        }
+    }
+
+    /// Catch up with the time, giving up if we've run out of it
+    fn tick(&mut self, now: Instant) -> Result<(), Error> {
+        self.now = Some(now);
+        let started = *self.started.get_or_insert(now);
+        if self
+            .deadline
+            .is_some_and(|deadline| now >= started + deadline)
+        {
+            debug!("│ Deadline passed");
+            return Err(Error::Timeout);
+        }
+        Ok(())
+    }
+
+    /// How long to wait for the server, which never goes past the deadline
+    fn timeout(&self, now: Instant) -> Duration {
+        let timeout = self.policy.timeout();
+        match (self.deadline, self.started) {
+            (Some(deadline), Some(started)) => {
+                timeout.min((started + deadline).saturating_duration_since(now))
+            }
+            _ => timeout,
+        }
+    }
+
+    /// The next datagram to send
+    fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        let transmit = self.outbox.pop_front()?;
+        self.started.get_or_insert(now);
+        if transmit.destination == self.server {
+            self.last_sent = Some(now);
+        }
+        if std::mem::take(&mut self.request_pending) {
+            self.event(Event::RequestSent);
+        }
+        Some(transmit)
+    }
+
+    /// Let the observer know what happened, if there is one
+    fn event(&self, event: Event) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// We're making progress, so undo any backoff
+    fn progress(&mut self) {
+        // Following Karn's algorithm, only replies to packets we sent once tell us the round-trip
+        // time
+        let rtt = self
+            .now
+            .zip(self.last_sent)
+            .filter(|_| !self.retransmitted)
+            .map(|(now, sent)| now.saturating_duration_since(sent));
+        self.retransmitted = false;
+        self.policy.on_progress(rtt);
+    }
+
+    /// Queue up a packet for the server
+    fn send(&mut self, pkt: &Packet) {
+        debug!("│ TX - {pkt}");
+        self.outbox.push_back(Transmit {
+            destination: self.server,
+            datagram: pkt.to_bytes(),
+        });
+    }
+
+    /// Queue up a packet for the server again
+    fn send_again(&mut self, pkt: &Packet) {
+        debug!("│ TX - {pkt} (Retry)");
+        self.retransmitted = true;
+        self.event(Event::Retransmit);
+        self.outbox.push_back(Transmit {
+            destination: self.server,
+            datagram: pkt.to_bytes(),
+        });
+    }
+
+    /// We timed out, so wait longer for the retry (or give up)
+    fn backoff(&mut self) -> Result<(), Error> {
+        debug!("│ Timeout");
+        self.event(Event::Timeout);
+        if !self.policy.on_timeout() {
+            return Err(Error::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Parse a datagram from the server, or `None` if it came from someone else
+    fn recv(&mut self, source: SocketAddr, datagram: &[u8]) -> Result<Option<Packet>, Error> {
+        let foreign = if self.tid_locked {
+            source != self.server
+        } else {
+            self.strict_host && source.ip() != self.initial_server.ip()
+        };
+        if foreign {
+            // As per RFC 1350 section 4, this doesn't disturb the transfer, but the sender gets
+            // an error
+            debug!("│ RX - Datagram from unknown transfer ID {source}");
+            let pkt = Packet::Error {
+                code: ErrorCode::BadId,
+                msg: CString::new("Unknown transfer ID").expect("No nulls in the message"),
+            };
+            debug!("│ TX - {pkt}");
+            self.outbox.push_back(Transmit {
+                destination: source,
+                datagram: pkt.to_bytes(),
+            });
+            return Ok(None);
+        }
+        let recv_pkt = Packet::from_bytes(datagram).map_err(Error::Parse)?;
+        debug!("│ RX - {recv_pkt}");
+        // The server picks a new port for the transfer (as the spec allows), which we hold it to
+        // from then on, but only once it sends us something that makes sense
+        self.server = source;
+        self.tid_locked = true;
+        Ok(Some(recv_pkt))
+    }
+
+    /// Go back to the start after the server refused our options
+    fn restart(&mut self) {
+        debug!("│ Options rejected, falling back to RFC 1350");
+        self.server = self.initial_server;
+        self.tid_locked = false;
+        self.request_pending = true;
+    }
+
+    /// Tell the server why we're giving up, if it's listening and didn't give up first
+    fn abort(&mut self, error: &Error) {
+        if self.aborted {
+            return;
+        }
+        self.aborted = true;
+        let code = match error {
+            // Until the server replies, there's no transfer for it to abort
+            _ if !self.tid_locked => return,
+            Error::Protocol { .. } | Error::BadFilename | Error::InvalidConfig(_) => return,
+            Error::Write(_) => ErrorCode::Write,
+            Error::Parse(_) | Error::UnexpectedPacket(_) => ErrorCode::Op,
+            Error::UnrequestedOption(_)
+            | Error::BadOptionValue(_)
+            | Error::UnsupportedOption(_) => ErrorCode::BadOpt,
+            Error::SocketIo(_) | Error::Read(_) | Error::Timeout => ErrorCode::Unspec,
+        };
+        let pkt = Packet::Error {
+            code,
+            msg: CString::new(error.to_string()).unwrap_or_default(),
+        };
+        self.send(&pkt);
+        debug!("└");
+    }
+
+    /// Let the retry policy know how often the server retransmits, and the observer what we
+    /// settled on
+    fn negotiated(&mut self, negotiated: &Negotiated) {
+        if let Some(server_timeout) = negotiated.timeout {
+            self.policy.on_server_timeout(server_timeout);
+        }
+        self.event(Event::Negotiated {
+            blksize: negotiated.blksize,
+            windowsize: negotiated.windowsize,
+            tsize: negotiated.tsize,
+            timeout: negotiated.timeout,
+        });
+    }
+}
+
+fn protocol_error(code: ErrorCode, msg: CString) -> Error {
+    Error::Protocol {
+        code,
+        msg: msg.into_string().expect("Error message had invalid UTF-8"),
+    }
+}
+
+/// Makes a fresh retry policy for every attempt at a transfer
+pub(crate) type NewPolicy<'a> = dyn Fn() -> Box<dyn RetryPolicy> + Sync + 'a;
+
+/// Makes the retry policy for every attempt at a transfer, for the functions that take a
+/// `timeout`, `max_timeout` and number of `retries`
+pub(crate) fn exponential(
+    timeout: Duration,
+    max_timeout: Duration,
+    retries: usize,
+) -> impl Fn() -> Box<dyn RetryPolicy> + Sync {
+    move || Box::new(Exponential::new(timeout, max_timeout, retries))
+}
+
+/// Whether to start a transfer over after `attempts` tries since `started` ended with `error`
+pub(crate) fn start_over(
+    options: &Options,
+    started: Instant,
+    attempts: usize,
+    error: &Error,
+) -> bool {
+    attempts < options.transfer_retries
+        && error.is_transient()
+        && options
+            .deadline
+            .map_or(true, |deadline| started.elapsed() < deadline)
+}
+
+/// A download, starting with a RRQ
+#[derive(Debug)]
+pub struct ReadTransfer {
+    link: Link,
+    filename: CString,
+    mode: RequestMode,
+    requested: Vec<TransferOption>,
+    negotiating: bool,
+    negotiated: Negotiated,
+    // The last packet we sent, in case we have to send it again
+    send_pkt: Packet,
+    // Netascii (and mail, which is netascii for a user) has to be translated back as it arrives
+    decoder: Option<netascii::Decoder>,
+    // Windowing state as per RFC 7440, the windowsize defaults to 1 which is RFC 1350 lockstep.
+    // Blocks are counted from the start of the transfer, as block numbers wrap around.
+    rollover: Rollover,
+    expected_block: u64,
+    window_count: usize,
+    gap_acked: bool,
+    // The data that's arrived but hasn't been collected yet, and how much has arrived in all
+    data: VecDeque<Vec<u8>>,
+    received: u64,
+    done: bool,
+    // How long to wait around after the final ack, in case it went missing
+    dally: Option<Duration>,
+    dallying: bool,
+    // Whether we only want the size of the file, and stop once the server tells us
+    query: bool,
+}
+
+impl ReadTransfer {
+    /// Start downloading a file via tftp, negotiating `options` with the server
+    pub fn new<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        server: SocketAddr,
+        timeout: Duration,
+        max_timeout: Duration,
+        retries: usize,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let policy = Exponential::new(timeout, max_timeout, retries);
+        Self::with_policy(filename, server, Box::new(policy), options)
+    }
+
+    /// Start downloading a file via tftp, negotiating `options` with the server and retrying as
+    /// `policy` sees fit
+    pub fn with_policy<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        Self::start(filename, server, policy, options, false)
+    }
+
+    /// Start querying the size of a file via tftp, sending a RRQ with the `tsize` option as per
+    /// [RFC 2349](https://datatracker.ietf.org/doc/html/rfc2349) and retrying as `policy` sees fit
+    ///
+    /// The transfer is done as soon as the server acknowledges the option (and
+    /// [`transfer_size`](ReadTransfer::transfer_size) has the answer), at which point we tell
+    /// the server we won't go through with it. Only the `strict_host` and `deadline` `options`
+    /// apply.
+    pub fn query_size<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let options = Options {
+            tsize: true,
+            strict_host: options.strict_host,
+            deadline: options.deadline,
+            ..Default::default()
+        };
+        Self::start(filename, server, policy, &options, true)
+    }
+
+    fn start<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+        query: bool,
+    ) -> Result<Self, Error> {
+        let filename = CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?;
+        let requested = options.to_request(Some(0))?;
+        if query {
+            debug!("┌── SIZE {}", filename.to_string_lossy());
+        } else {
+            debug!("┌── GET {}", filename.to_string_lossy());
+        }
+        let mut transfer = Self {
+            link: Link::new(server, policy, options),
+            send_pkt: Packet::ReadRequest {
+                filename: filename.clone(),
+                mode: options.mode,
+                options: requested.clone(),
+            },
+            filename,
+            mode: options.mode,
+            negotiating: !requested.is_empty(),
+            requested,
+            // Until the server acknowledges our options, we use the RFC 1350 defaults
+            negotiated: Negotiated::default(),
+            decoder: (options.mode != RequestMode::Octet).then(netascii::Decoder::default),
+            rollover: options.rollover,
+            expected_block: 1,
+            window_count: 0,
+            gap_acked: false,
+            data: VecDeque::new(),
+            received: 0,
+            done: false,
+            dally: options.dally,
+            dallying: false,
+            query,
+        };
+        transfer.link.send(&transfer.send_pkt);
+        Ok(transfer)
+    }
+
+    /// Tell `observer` about everything that happens from here on
+    pub fn observe(&mut self, observer: Arc<dyn Observer>) {
+        self.link.observer = Some(observer);
+    }
+
+    /// Count the deadline from `started`, for when the transfer started over
+    pub(crate) fn started_at(&mut self, started: Instant) {
+        self.link.started = Some(started);
+    }
+
+    /// The next chunk of the file, in order, if one has arrived
+    pub fn poll_data(&mut self) -> Option<Vec<u8>> {
+        self.data.pop_front()
+    }
+
+    /// Whether there's data waiting to be collected with [`poll_data`](ReadTransfer::poll_data)
+    pub fn has_data(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    /// Whether the whole file has arrived (although some of it may not be collected yet)
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The size of the file, if the server told us as per RFC 2349
+    pub fn transfer_size(&self) -> Option<u64> {
+        self.negotiated.tsize
+    }
+
+    /// Send a packet that moves the transfer along
+    fn send(&mut self, pkt: Packet) {
+        self.link.progress();
+        self.link.send(&pkt);
+        self.send_pkt = pkt;
+    }
+}
+
+impl Transfer for ReadTransfer {
+    fn on_datagram(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<(), Error> {
+        if self.done {
+            // If the server resends the final block, our ack must have gone missing
+            if self.dallying {
+                if let Some(Packet::Data { block_n, .. }) = self.link.recv(source, datagram)? {
+                    if self.rollover.position(block_n, self.expected_block) + 1
+                        == self.expected_block
+                    {
+                        self.link.send_again(&self.send_pkt);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        self.link.tick(now)?;
+        let Some(recv_pkt) = self.link.recv(source, datagram)? else {
+            return Ok(());
+        };
+        match recv_pkt {
+            Packet::Data { .. } if self.query => {
+                // The server ignored our option and started sending the file
+                Err(Error::UnsupportedOption("tsize".to_owned()))
+            }
+            Packet::Data { block_n, data } => {
+                self.negotiating = false;
+                // How far ahead of the block we expect this one is
+                let ahead = self.rollover.position(block_n, self.expected_block) as i64
+                    - self.expected_block as i64;
+                let last_acked = matches!(
+                    self.send_pkt,
+                    Packet::Acknowledgment { block_n: acked } if acked == block_n
+                );
+                if ahead == -1 && last_acked {
+                    // The server resent the block we last acked, so our ack must have gone
+                    // missing. Ack it again, but don't keep the data twice. Any other block we
+                    // already have is part of a window the server is resending, and acking it
+                    // would restart the window part way through. This is the server
+                    // retransmitting, so it doesn't tell us the round-trip time.
+                    self.gap_acked = false;
+                    self.window_count = 0;
+                    self.link.send_again(&self.send_pkt);
+                    return Ok(());
+                }
+                if ahead < 0 {
+                    // Anything older is a leftover from a window the server already resent
+                    debug!("│ Ignoring stale block {block_n}");
+                    return Ok(());
+                }
+                if ahead as usize >= self.negotiated.windowsize {
+                    // The server can't be further along than a window past our last ack
+                    return Err(Error::UnexpectedPacket(Packet::Data { block_n, data }));
+                }
+                if ahead != 0 {
+                    // A block went missing, so we ack the last one we got in order and the server
+                    // will resend from there. Only do this once per gap so we don't restart the
+                    // window for every block still in flight.
+                    if !self.gap_acked {
+                        self.gap_acked = true;
+                        self.window_count = 0;
+                        self.send(Packet::Acknowledgment {
+                            block_n: self.rollover.block_n(self.expected_block - 1),
+                        });
+                    }
+                    return Ok(());
+                }
+                // We got back the next chunk of data, hold on to it until it's collected
+                self.gap_acked = false;
+                let mut block = match &mut self.decoder {
+                    Some(decoder) => {
+                        let mut decoded = vec![];
+                        decoder.decode(&data, &mut decoded);
+                        decoded
+                    }
+                    None => data.clone(),
+                };
+                self.received += data.len() as u64;
+                self.link.event(Event::Block {
+                    block: self.expected_block,
+                    bytes: self.received,
+                    total: self.negotiated.tsize,
+                });
+                self.expected_block += 1;
+                self.window_count += 1;
+                let done = data.len() < self.negotiated.blksize;
+                // Only ack at the end of a window (or the end of the file)
+                if done || self.window_count == self.negotiated.windowsize {
+                    self.window_count = 0;
+                    self.send(Packet::Acknowledgment { block_n });
+                }
+                if done {
+                    if let Some(decoder) = self.decoder.take() {
+                        decoder.finish(&mut block);
+                    }
+                    self.done = true;
+                    self.dallying = self.dally.is_some();
+                    self.link.event(Event::Complete {
+                        bytes: self.received,
+                    });
+                    debug!("└");
+                }
+                self.data.push_back(block);
+                Ok(())
+            }
+            Packet::OptionAcknowledgment { .. }
+                if self.negotiating
+                    && matches!(self.send_pkt, Packet::Acknowledgment { block_n: 0 }) =>
+            {
+                // The server resent its OACK, so our ack must have gone missing. Ack it again,
+                // but the options are already settled, and this is the server retransmitting so
+                // it doesn't tell us the round-trip time.
+                self.link.send_again(&self.send_pkt);
+                Ok(())
+            }
+            Packet::OptionAcknowledgment {
+                options: acknowledged,
+            } if self.negotiating => {
+                // The server accepted our options, so we acknowledge with block 0 to start the
+                // transfer
+                self.negotiated = Options::negotiate(&self.requested, &acknowledged)?;
+                self.link.negotiated(&self.negotiated);
+                if let Some(tsize) = self.negotiated.tsize {
+                    debug!("│ Transfer size: {tsize}");
+                }
+                if self.query {
+                    // That's all we wanted to know, so tell the server we're not going to go
+                    // through with the transfer
+                    self.done = true;
+                    self.link.aborted = true;
+                    self.link.send(&Packet::Error {
+                        code: ErrorCode::Unspec,
+                        msg: CString::new("Transfer size query complete")
+                            .expect("No nulls in the message"),
+                    });
+                    debug!("└");
+                    return Ok(());
+                }
+                self.send(Packet::Acknowledgment { block_n: 0 });
+                Ok(())
+            }
+            Packet::Error {
+                code: ErrorCode::BadOpt,
+                ..
+            } if self.negotiating
+                && !self.query
+                && matches!(self.send_pkt, Packet::ReadRequest { .. }) =>
+            {
+                // The server refused our options, so fall back to a plain RFC 1350 request
+                self.link.restart();
+                self.requested.clear();
+                self.negotiating = false;
+                self.send(Packet::ReadRequest {
+                    filename: self.filename.clone(),
+                    mode: self.mode,
+                    options: vec![],
+                });
+                Ok(())
+            }
+            Packet::Error { code, msg } => Err(protocol_error(code, msg)),
+            recv_pkt => Err(Error::UnexpectedPacket(recv_pkt)),
+        }
+    }
+
+    fn on_timeout(&mut self, now: Instant) -> Result<(), Error> {
+        if self.done {
+            // The server would have resent the final block by now if our ack went missing
+            self.dallying = false;
+            return Ok(());
+        }
+        self.link.tick(now)?;
+        self.link.backoff()?;
+        if matches!(self.send_pkt, Packet::Acknowledgment { .. }) {
+            // As per RFC 7440, ack the last block we got in order (which may be part way through
+            // a window), so the server resends from there rather than from our last ack
+            self.window_count = 0;
+            self.gap_acked = false;
+            self.send_pkt = Packet::Acknowledgment {
+                block_n: self.rollover.block_n(self.expected_block - 1),
+            };
+        }
+        // Try sending the last packet again
+        self.link.send_again(&self.send_pkt);
+        Ok(())
+    }
+
+    fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        self.link.poll_transmit(now)
+    }
+
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        if self.link.aborted {
+            return None;
+        }
+        if self.done {
+            return self.dally.filter(|_| self.dallying);
+        }
+        Some(self.link.timeout(now))
+    }
+
+    fn max_datagram_len(&self) -> usize {
+        // The biggest a block can be, 2 bytes for opcode, 2 bytes for block n
+        self.negotiated.blksize + 4
+    }
+
+    fn abort(&mut self, error: &Error) {
+        // Once we've acked the final block, the server has nothing left to abort
+        if !self.done {
+            self.link.abort(error);
+        }
+    }
+}
+
+/// An upload, starting with a WRQ
+#[derive(Debug)]
+pub struct WriteTransfer {
+    link: Link,
+    filename: CString,
+    mode: RequestMode,
+    requested: Vec<TransferOption>,
+    negotiating: bool,
+    negotiated: Negotiated,
+    // The blocks we've sent but haven't been acked yet, following `last_acked`, which counts
+    // blocks from the start of the transfer as block numbers wrap around
+    window: VecDeque<Vec<u8>>,
+    rollover: Rollover,
+    last_acked: Option<u64>,
+    // Data that doesn't fill a block (or fit in the window) yet, translated to netascii if need be
+    pending: Vec<u8>,
+    written: u64,
+    // What the server has acked so far, for progress reporting
+    size: Option<u64>,
+    acked_bytes: u64,
+    finished: bool,
+    final_sent: bool,
+    done: bool,
+}
+
+impl WriteTransfer {
+    /// Start uploading a file via tftp, negotiating `options` with the server
+    ///
+    /// `size` is the size of the file (once translated to netascii if need be), which is sent
+    /// along with the request if `options` asks for the `tsize` option
+    pub fn new<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        size: Option<u64>,
+        server: SocketAddr,
+        timeout: Duration,
+        max_timeout: Duration,
+        retries: usize,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let policy = Exponential::new(timeout, max_timeout, retries);
+        Self::with_policy(filename, size, server, Box::new(policy), options)
+    }
+
+    /// Start uploading a file via tftp, negotiating `options` with the server and retrying as
+    /// `policy` sees fit
+    pub fn with_policy<T: AsRef<str> + std::fmt::Display>(
+        filename: T,
+        size: Option<u64>,
+        server: SocketAddr,
+        policy: Box<dyn RetryPolicy>,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let filename = CString::new(filename.to_string()).map_err(|_| Error::BadFilename)?;
+        let requested = options.to_request(size)?;
+        debug!("┌── PUT {}", filename.to_string_lossy());
+        let mut transfer = Self {
+            link: Link::new(server, policy, options),
+            filename,
+            mode: options.mode,
+            negotiating: !requested.is_empty(),
+            requested,
+            // Until the server acknowledges our options, we use the RFC 1350 defaults
+            negotiated: Negotiated::default(),
+            window: VecDeque::new(),
+            rollover: options.rollover,
+            last_acked: None,
+            pending: vec![],
+            written: 0,
+            size,
+            acked_bytes: 0,
+            finished: false,
+            final_sent: false,
+            done: false,
+        };
+        let request = transfer.request();
+        transfer.link.send(&request);
+        Ok(transfer)
+    }
+
+    /// Tell `observer` about everything that happens from here on
+    pub fn observe(&mut self, observer: Arc<dyn Observer>) {
+        self.link.observer = Some(observer);
+    }
+
+    /// Count the deadline from `started`, for when the transfer started over
+    pub(crate) fn started_at(&mut self, started: Instant) {
+        self.link.started = Some(started);
+    }
+
+    /// Add `data` to the upload, which is sent out as blocks fill up and there's room in the
+    /// window
+    pub fn write(&mut self, data: &[u8]) {
+        self.written += data.len() as u64;
+        match self.mode {
+            RequestMode::Octet => self.pending.extend_from_slice(data),
+            _ => netascii::encode(data, &mut self.pending),
+        }
+        self.fill_window();
+    }
+
+    /// Mark the end of the data, which sends the final (short, or even empty) block
+    pub fn finish(&mut self) {
+        self.finished = true;
+        self.fill_window();
+    }
+
+    /// Whether there's room for more data, as otherwise it just piles up until the server catches
+    /// up
+    pub fn wants_data(&self) -> bool {
+        !self.finished
+            && self.last_acked.is_some()
+            && self.window.len() < self.negotiated.windowsize
+            && self.pending.len() < self.negotiated.blksize
+    }
+
+    /// Whether the server has acknowledged the whole file
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The block size for the transfer, which is only final once the server has responded
+    pub fn blksize(&self) -> usize {
+        self.negotiated.blksize
+    }
+
+    fn request(&self) -> Packet {
+        Packet::WriteRequest {
+            filename: self.filename.clone(),
+            mode: self.mode,
+            options: self.requested.clone(),
+        }
+    }
+
+    /// The DATA packet for the block at `i` in the window
+    fn data(&self, i: usize) -> Packet {
+        Packet::Data {
+            block_n: self
+                .rollover
+                .block_n(self.last_acked.unwrap_or(0) + i as u64 + 1),
+            data: self.window[i].clone(),
+        }
+    }
+
+    /// Send out whatever blocks we can
+    fn fill_window(&mut self) {
+        // Nothing can be sent until the server responds to the request, or once we've given up
+        if self.last_acked.is_none() || self.link.aborted {
+            return;
+        }
+        while self.window.len() < self.negotiated.windowsize && !self.final_sent {
+            let block = if self.pending.len() >= self.negotiated.blksize {
+                let rest = self.pending.split_off(self.negotiated.blksize);
+                std::mem::replace(&mut self.pending, rest)
+            } else if self.finished {
+                self.final_sent = true;
+                std::mem::take(&mut self.pending)
+            } else {
+                break;
+            };
+            self.window.push_back(block);
+            let pkt = self.data(self.window.len() - 1);
+            self.link.send(&pkt);
+        }
+    }
+}
+
+impl Transfer for WriteTransfer {
+    fn on_datagram(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<(), Error> {
+        if self.done {
+            return Ok(());
+        }
+        self.link.tick(now)?;
+        let Some(recv_pkt) = self.link.recv(source, datagram)? else {
+            return Ok(());
+        };
+        let block_n = match recv_pkt {
+            Packet::Acknowledgment { block_n } => block_n,
+            Packet::OptionAcknowledgment {
+                options: acknowledged,
+            } if self.negotiating && matches!(self.last_acked, None | Some(0)) => {
+                // The server accepted our options, which stands in for the ACK of block 0
+                self.negotiated = Options::negotiate(&self.requested, &acknowledged)?;
+                self.link.negotiated(&self.negotiated);
+                0
+            }
+            Packet::Error {
+                code: ErrorCode::BadOpt,
+                ..
+            } if self.negotiating && self.last_acked.is_none() => {
+                // The server refused our options, so fall back to a plain RFC 1350 request
+                self.link.restart();
+                self.requested.clear();
+                self.negotiating = false;
+                self.link.progress();
+                let request = self.request();
+                self.link.send(&request);
+                return Ok(());
+            }
+            Packet::Error { code, msg } => return Err(protocol_error(code, msg)),
+            recv_pkt => return Err(Error::UnexpectedPacket(recv_pkt)),
+        };
+        // Fix for https://en.wikipedia.org/wiki/Sorcerer%27s_Apprentice_Syndrome
+        // Just try to recv again and don't resend the data on duplicate (or stale) Acks
+        let last = self.last_acked.unwrap_or(0);
+        let position = self.rollover.position(block_n, last);
+        if self.last_acked.is_some() && position <= last {
+            return Ok(());
+        }
+        if position > last + self.window.len() as u64 {
+            // The server can't ack blocks we haven't sent yet
+            return Err(Error::UnexpectedPacket(Packet::Acknowledgment { block_n }));
+        }
+        let acked = (position - last) as usize;
+        self.last_acked = Some(position);
+        self.link.progress();
+        // We got back an ack, so everything up to it is done with
+        for (i, block) in self.window.drain(..acked).enumerate() {
+            self.acked_bytes += block.len() as u64;
+            self.link.event(Event::Block {
+                block: last + i as u64 + 1,
+                bytes: self.acked_bytes,
+                total: self.size,
+            });
+        }
+        if self.final_sent && self.window.is_empty() {
+            self.done = true;
+            self.link.event(Event::Complete {
+                bytes: self.acked_bytes,
+            });
+            debug!("└");
+            return Ok(());
+        }
+        // If this acked only part of the window, we roll back and send the rest again
+        for i in 0..self.window.len() {
+            let pkt = self.data(i);
+            self.link.send_again(&pkt);
+        }
+        self.fill_window();
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, now: Instant) -> Result<(), Error> {
+        if self.done {
+            return Ok(());
+        }
+        self.link.tick(now)?;
+        // Try sending everything in flight again
+        self.link.backoff()?;
+        if self.last_acked.is_none() {
+            let request = self.request();
+            self.link.send_again(&request);
+        }
+        for i in 0..self.window.len() {
+            let pkt = self.data(i);
+            self.link.send_again(&pkt);
+        }
+        Ok(())
+    }
+
+    fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        self.link.poll_transmit(now)
+    }
+
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        // We're only waiting on the server while something is in flight
+        let in_flight = self.last_acked.is_none() || !self.window.is_empty();
+        (!self.done && !self.link.aborted && in_flight).then(|| self.link.timeout(now))
+    }
+
+    fn max_datagram_len(&self) -> usize {
+        // Room for a whole DATA packet, which is plenty for the ACKs we expect back
+        self.negotiated.blksize + 4
+    }
+
+    fn abort(&mut self, error: &Error) {
+        if !self.done {
+            self.link.abort(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        retry::{
+            Constant,
+            Jacobson,
+        },
+        BLKSIZE,
+    };
+
+    fn server() -> SocketAddr {
+        "127.0.0.1:69".parse().unwrap()
+    }
+
+    fn tid() -> SocketAddr {
+        "127.0.0.1:4242".parse().unwrap()
+    }
+
+    fn transmits<X: Transfer>(transfer: &mut X) -> Vec<Packet> {
+        std::iter::from_fn(|| transfer.poll_transmit(Instant::now()))
+            .map(|transmit| Packet::from_bytes(&transmit.datagram).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let options = Options {
+            blksize: Some(8),
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        assert!(matches!(
+            transmits(&mut transfer)[..],
+            [Packet::ReadRequest { .. }]
+        ));
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![TransferOption::new("blksize", "8").unwrap()],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 0 }]
+        );
+        for (block_n, data) in [(1, &b"01234567"[..]), (2, b"89")] {
+            let pkt = Packet::Data {
+                block_n,
+                data: data.to_vec(),
+            };
+            transfer
+                .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+                .unwrap();
+            assert_eq!(
+                transmits(&mut transfer),
+                [Packet::Acknowledgment { block_n }]
+            );
+            assert_eq!(transfer.poll_data().unwrap(), data);
+        }
+        assert!(transfer.is_done());
+        assert_eq!(transfer.timeout(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_query_size() {
+        let policy = Box::new(Constant::new(Duration::from_secs(1), 4));
+        let mut transfer =
+            ReadTransfer::query_size("foo", server(), policy, &Options::default()).unwrap();
+        let [Packet::ReadRequest { options, .. }] = &transmits(&mut transfer)[..] else {
+            panic!("Expected a RRQ");
+        };
+        assert_eq!(options, &[TransferOption::new("tsize", "0").unwrap()]);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![TransferOption::new("tsize", "1234").unwrap()],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        // We stop there and let the server know, rather than acking the options
+        assert!(matches!(
+            transmits(&mut transfer)[..],
+            [Packet::Error {
+                code: ErrorCode::Unspec,
+                ..
+            }]
+        ));
+        assert!(transfer.is_done());
+        assert_eq!(transfer.timeout(Instant::now()), None);
+        assert_eq!(transfer.transfer_size(), Some(1234));
+
+        // A server that ignores the option can't tell us the size
+        let policy = Box::new(Constant::new(Duration::from_secs(1), 4));
+        let mut transfer =
+            ReadTransfer::query_size("foo", server(), policy, &Options::default()).unwrap();
+        transmits(&mut transfer);
+        let data = Packet::Data {
+            block_n: 1,
+            data: b"foo".to_vec(),
+        };
+        assert!(matches!(
+            transfer.on_datagram(Instant::now(), tid(), &data.to_bytes()),
+            Err(Error::UnsupportedOption(_))
+        ));
+    }
+
+    #[test]
+    fn test_foreign_tid() {
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let data = |block_n: u16| {
+            Packet::Data {
+                block_n,
+                data: vec![0; BLKSIZE],
+            }
+            .to_bytes()
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &data(1))
+            .unwrap();
+        transmits(&mut transfer);
+        // Someone else chiming in gets an error, and the transfer carries on without them
+        let intruder = "127.0.0.1:4343".parse().unwrap();
+        transfer
+            .on_datagram(Instant::now(), intruder, &data(2))
+            .unwrap();
+        let transmit = transfer.poll_transmit(Instant::now()).unwrap();
+        assert_eq!(transmit.destination, intruder);
+        assert!(matches!(
+            Packet::from_bytes(&transmit.datagram).unwrap(),
+            Packet::Error {
+                code: ErrorCode::BadId,
+                ..
+            }
+        ));
+        assert!(transfer.poll_transmit(Instant::now()).is_none());
+        transfer
+            .on_datagram(Instant::now(), tid(), &data(2))
+            .unwrap();
+        let transmit = transfer.poll_transmit(Instant::now()).unwrap();
+        assert_eq!(transmit.destination, tid());
+        assert_eq!(
+            Packet::from_bytes(&transmit.datagram).unwrap(),
+            Packet::Acknowledgment { block_n: 2 }
+        );
+    }
+
+    #[test]
+    fn test_strict_host() {
+        let options = Options {
+            strict_host: true,
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        // A reply from another host doesn't lock in the transfer ID
+        let pkt = Packet::Data {
+            block_n: 1,
+            data: b"foo".to_vec(),
+        };
+        transfer
+            .on_datagram(
+                Instant::now(),
+                "127.0.0.2:4242".parse().unwrap(),
+                &pkt.to_bytes(),
+            )
+            .unwrap();
+        assert!(matches!(
+            transmits(&mut transfer)[..],
+            [Packet::Error {
+                code: ErrorCode::BadId,
+                ..
+            }]
+        ));
+        assert!(!transfer.is_done());
+        transfer
+            .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+            .unwrap();
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn test_garbage_before_reply() {
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        // Something that doesn't parse doesn't lock in the transfer ID, so there's no one to tell
+        // when we give up
+        let err = transfer
+            .on_datagram(Instant::now(), tid(), &[0, 42])
+            .unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        transfer.abort(&err);
+        assert!(transmits(&mut transfer).is_empty());
+    }
+
+    #[test]
+    fn test_read_duplicates() {
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let data = |block_n: u16| {
+            Packet::Data {
+                block_n,
+                data: vec![block_n as u8; BLKSIZE],
+            }
+            .to_bytes()
+        };
+        for block_n in 1..=2 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &data(block_n))
+                .unwrap();
+        }
+        transmits(&mut transfer);
+        // Our ack of block 2 went missing, so it's acked again but only kept once
+        transfer
+            .on_datagram(Instant::now(), tid(), &data(2))
+            .unwrap();
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 2 }]
+        );
+        // Anything older is ignored
+        transfer
+            .on_datagram(Instant::now(), tid(), &data(1))
+            .unwrap();
+        assert!(transmits(&mut transfer).is_empty());
+        let collected: Vec<_> = std::iter::from_fn(|| transfer.poll_data()).collect();
+        assert_eq!(collected, [vec![1; BLKSIZE], vec![2; BLKSIZE]]);
+        // Without a window, the server can't skip ahead
+        assert!(matches!(
+            transfer.on_datagram(Instant::now(), tid(), &data(4)),
+            Err(Error::UnexpectedPacket(Packet::Data { block_n: 4, .. }))
+        ));
+    }
+
+    /// A download of 8 byte blocks, 4 to a window
+    fn windowed_read() -> ReadTransfer {
+        let options = Options {
+            blksize: Some(8),
+            windowsize: Some(4),
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![
+                TransferOption::new("blksize", "8").unwrap(),
+                TransferOption::new("windowsize", "4").unwrap(),
+            ],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        transmits(&mut transfer);
+        transfer
+    }
+
+    fn block(block_n: u16) -> Vec<u8> {
+        Packet::Data {
+            block_n,
+            data: vec![block_n as u8; 8],
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_read_window_timeout() {
+        let mut transfer = windowed_read();
+        for block_n in 1..=6 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &block(block_n))
+                .unwrap();
+        }
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 4 }]
+        );
+        // The rest of the window went missing, so we ack what we got rather than the last window
+        transfer.on_timeout(Instant::now()).unwrap();
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 6 }]
+        );
+        // And the server's next window starts from there
+        for block_n in 7..=10 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &block(block_n))
+                .unwrap();
+        }
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 10 }]
+        );
+    }
+
+    #[test]
+    fn test_read_window_resent() {
+        let mut transfer = windowed_read();
+        for block_n in 1..=6 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &block(block_n))
+                .unwrap();
+        }
+        transmits(&mut transfer);
+        // The server timed out and resends the window from our last ack, the blocks we already
+        // have don't get acked part way through the window
+        for block_n in 5..=6 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &block(block_n))
+                .unwrap();
+        }
+        assert!(transmits(&mut transfer).is_empty());
+        for block_n in 7..=8 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &block(block_n))
+                .unwrap();
+        }
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 8 }]
+        );
+        // But the block at the end of the window is acked again, as our ack went missing
+        transfer
+            .on_datagram(Instant::now(), tid(), &block(8))
+            .unwrap();
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 8 }]
+        );
+        let collected: Vec<_> = std::iter::from_fn(|| transfer.poll_data()).collect();
+        assert_eq!(collected, (1..=8).map(|n| vec![n; 8]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rollover() {
+        for (rollover, wrapped) in [(Rollover::Zero, 0), (Rollover::One, 1)] {
+            let options = Options {
+                blksize: Some(8),
+                windowsize: Some(4),
+                rollover,
+                ..Default::default()
+            };
+            let mut upload = WriteTransfer::new(
+                "foo",
+                None,
+                server(),
+                Duration::from_secs(1),
+                Duration::from_secs(5),
+                4,
+                &options,
+            )
+            .unwrap();
+            let mut download = ReadTransfer::new(
+                "foo",
+                server(),
+                Duration::from_secs(1),
+                Duration::from_secs(5),
+                4,
+                &options,
+            )
+            .unwrap();
+            transmits(&mut upload);
+            transmits(&mut download);
+            let oack = Packet::OptionAcknowledgment {
+                options: vec![
+                    TransferOption::new("blksize", "8").unwrap(),
+                    TransferOption::new("windowsize", "4").unwrap(),
+                ],
+            }
+            .to_bytes();
+            upload.on_datagram(Instant::now(), tid(), &oack).unwrap();
+            download.on_datagram(Instant::now(), tid(), &oack).unwrap();
+            transmits(&mut download);
+            // Pass the blocks straight from one transfer to the other, past the wrap
+            let blocks = u16::MAX as usize + 3;
+            upload.write(&vec![7; blocks * 8 + 3]);
+            upload.finish();
+            let mut block_ns = vec![];
+            while !upload.is_done() {
+                for transmit in std::iter::from_fn(|| upload.poll_transmit(Instant::now())) {
+                    if let Packet::Data { block_n, .. } =
+                        Packet::from_bytes(&transmit.datagram).unwrap()
+                    {
+                        block_ns.push(block_n);
+                    }
+                    download
+                        .on_datagram(Instant::now(), tid(), &transmit.datagram)
+                        .unwrap();
+                }
+                for transmit in std::iter::from_fn(|| download.poll_transmit(Instant::now())) {
+                    upload
+                        .on_datagram(Instant::now(), tid(), &transmit.datagram)
+                        .unwrap();
+                }
+            }
+            assert_eq!(block_ns.len(), blocks + 1);
+            assert_eq!(
+                block_ns[u16::MAX as usize - 1..][..3],
+                [u16::MAX, wrapped, wrapped + 1]
+            );
+            assert!(download.is_done());
+            let received: usize = std::iter::from_fn(|| download.poll_data())
+                .map(|data| data.len())
+                .sum();
+            assert_eq!(received, blocks * 8 + 3);
+        }
+    }
+
+    #[test]
+    fn test_read_dally() {
+        let options = Options {
+            dally: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let pkt = Packet::Data {
+            block_n: 1,
+            data: b"foo".to_vec(),
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+            .unwrap();
+        assert!(transfer.is_done());
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 1 }]
+        );
+        // The final block comes around again as our ack went missing, so we ack it again
+        assert_eq!(
+            transfer.timeout(Instant::now()),
+            Some(Duration::from_secs(2))
+        );
+        transfer
+            .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+            .unwrap();
+        assert_eq!(
+            transmits(&mut transfer),
+            [Packet::Acknowledgment { block_n: 1 }]
+        );
+        assert_eq!(transfer.poll_data().unwrap(), b"foo");
+        assert!(transfer.poll_data().is_none());
+        transfer.on_timeout(Instant::now()).unwrap();
+        assert_eq!(transfer.timeout(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_write_final_empty_block() {
+        let options = Options {
+            blksize: Some(8),
+            ..Default::default()
+        };
+        let mut transfer = WriteTransfer::new(
+            "foo",
+            None,
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![TransferOption::new("blksize", "8").unwrap()],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        transfer.write(b"0123456789abcdef");
+        transfer.finish();
+        // A whole number of blocks is followed by an empty one, so the server knows it's over
+        let mut sent = vec![];
+        for block_n in 1..=3 {
+            sent.extend(transmits(&mut transfer));
+            let ack = Packet::Acknowledgment { block_n };
+            transfer
+                .on_datagram(Instant::now(), tid(), &ack.to_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            sent,
+            [
+                Packet::Data {
+                    block_n: 1,
+                    data: b"01234567".to_vec()
+                },
+                Packet::Data {
+                    block_n: 2,
+                    data: b"89abcdef".to_vec()
+                },
+                Packet::Data {
+                    block_n: 3,
+                    data: vec![]
+                },
+            ]
+        );
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let pkt = Packet::Data {
+            block_n: 1,
+            data: vec![0; BLKSIZE],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+            .unwrap();
+        transmits(&mut transfer);
+        // The server hears about it, and then we're done waiting on it
+        let error = Error::Write(std::io::Error::other("Disk full"));
+        transfer.abort(&error);
+        let transmit = transfer.poll_transmit(Instant::now()).unwrap();
+        assert_eq!(transmit.destination, tid());
+        assert!(matches!(
+            Packet::from_bytes(&transmit.datagram).unwrap(),
+            Packet::Error {
+                code: ErrorCode::Write,
+                ..
+            }
+        ));
+        assert_eq!(transfer.timeout(Instant::now()), None);
+        // Only once, though
+        transfer.abort(&error);
+        assert!(transfer.poll_transmit(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_abort_before_reply() {
+        let mut transfer = WriteTransfer::new(
+            "foo",
+            None,
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        // There's no transfer on the server's side to abort yet
+        transfer.abort(&Error::Timeout);
+        assert!(transfer.poll_transmit(Instant::now()).is_none());
+        assert_eq!(transfer.timeout(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            3,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        // The request goes out again with backoff, until we run out of retries
+        transfer.on_timeout(Instant::now()).unwrap();
+        assert!(matches!(
+            transmits(&mut transfer)[..],
+            [Packet::ReadRequest { .. }]
+        ));
+        assert_eq!(
+            transfer.timeout(Instant::now()),
+            Some(Duration::from_millis(1500))
+        );
+        transfer.on_timeout(Instant::now()).unwrap();
+        assert_eq!(
+            transfer.timeout(Instant::now()),
+            Some(Duration::from_secs(2))
+        );
+        assert!(matches!(
+            transfer.on_timeout(Instant::now()),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let options = Options {
+            deadline: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        let policy = Box::new(Constant::new(Duration::from_secs(1), 100));
+        let start = Instant::now();
+        let mut transfer = ReadTransfer::with_policy("foo", server(), policy, &options).unwrap();
+        transmits(&mut transfer);
+        transfer.on_timeout(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            transfer.timeout(start + Duration::from_secs(1)),
+            Some(Duration::from_secs(1))
+        );
+        // Plenty of retries are left, but we never wait past the deadline
+        assert!(
+            transfer
+                .timeout(start + Duration::from_millis(2500))
+                .unwrap()
+                < Duration::from_secs(1)
+        );
+        assert!(matches!(
+            transfer.on_timeout(start + Duration::from_secs(4)),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_karn() {
+        let policy = Box::new(Jacobson::new(
+            Duration::from_secs(1),
+            Duration::from_secs(8),
+            4,
+        ));
+        let mut transfer =
+            ReadTransfer::with_policy("foo", server(), policy, &Options::default()).unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let data = |block_n: u16| {
+            Packet::Data {
+                block_n,
+                data: vec![0; BLKSIZE],
+            }
+            .to_bytes()
+        };
+        let flush =
+            |transfer: &mut ReadTransfer, now| while transfer.poll_transmit(now).is_some() {};
+        flush(&mut transfer, at(0));
+        transfer.on_datagram(at(100), tid(), &data(1)).unwrap();
+        flush(&mut transfer, at(100));
+        assert_eq!(transfer.timeout(at(100)), Some(Duration::from_millis(300)));
+        // The ack goes out again, so the next reply doesn't tell us anything
+        transfer.on_timeout(at(400)).unwrap();
+        flush(&mut transfer, at(400));
+        transfer.on_datagram(at(450), tid(), &data(2)).unwrap();
+        flush(&mut transfer, at(450));
+        assert_eq!(transfer.timeout(at(450)), Some(Duration::from_millis(600)));
+        // But the one after that does
+        transfer.on_datagram(at(550), tid(), &data(3)).unwrap();
+        assert_eq!(transfer.timeout(at(550)), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_karn_retransmits() {
+        let jacobson = || {
+            Box::new(Jacobson::new(
+                Duration::from_secs(1),
+                Duration::from_secs(8),
+                4,
+            ))
+        };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let flush =
+            |transfer: &mut dyn Transfer, now| while transfer.poll_transmit(now).is_some() {};
+
+        // The server resending a block it timed out on doesn't tell us the round-trip time
+        let mut transfer =
+            ReadTransfer::with_policy("foo", server(), jacobson(), &Options::default()).unwrap();
+        flush(&mut transfer, at(0));
+        let data = Packet::Data {
+            block_n: 1,
+            data: vec![0; BLKSIZE],
+        };
+        transfer
+            .on_datagram(at(100), tid(), &data.to_bytes())
+            .unwrap();
+        flush(&mut transfer, at(100));
+        transfer
+            .on_datagram(at(1100), tid(), &data.to_bytes())
+            .unwrap();
+        assert_eq!(transfer.timeout(at(1100)), Some(Duration::from_millis(300)));
+
+        // Neither does an ack for part of a window we had to send again
+        let options = Options {
+            blksize: Some(8),
+            windowsize: Some(2),
+            ..Default::default()
+        };
+        let mut transfer =
+            WriteTransfer::with_policy("foo", None, server(), jacobson(), &options).unwrap();
+        flush(&mut transfer, at(0));
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![
+                TransferOption::new("blksize", "8").unwrap(),
+                TransferOption::new("windowsize", "2").unwrap(),
+            ],
+        };
+        transfer
+            .on_datagram(at(100), tid(), &oack.to_bytes())
+            .unwrap();
+        transfer.write(&[0; 24]);
+        transfer.finish();
+        flush(&mut transfer, at(100));
+        let ack = |block_n: u16| Packet::Acknowledgment { block_n }.to_bytes();
+        transfer.on_datagram(at(200), tid(), &ack(1)).unwrap();
+        assert_eq!(transfer.timeout(at(200)), Some(Duration::from_millis(250)));
+        flush(&mut transfer, at(200));
+        transfer.on_datagram(at(1200), tid(), &ack(3)).unwrap();
+        assert_eq!(transfer.timeout(at(1200)), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_write_ack_ahead() {
+        let mut transfer = WriteTransfer::new(
+            "foo",
+            None,
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        transmits(&mut transfer);
+        let ack = |block_n: u16| Packet::Acknowledgment { block_n }.to_bytes();
+        transfer
+            .on_datagram(Instant::now(), tid(), &ack(0))
+            .unwrap();
+        transfer.write(&[0; 600]);
+        assert_eq!(transmits(&mut transfer).len(), 1);
+        // Only block 1 is in flight, so the server can't have gotten block 3
+        assert!(matches!(
+            transfer.on_datagram(Instant::now(), tid(), &ack(3)),
+            Err(Error::UnexpectedPacket(Packet::Acknowledgment {
+                block_n: 3
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_write_window() {
+        let options = Options {
+            blksize: Some(8),
+            windowsize: Some(2),
+            ..Default::default()
+        };
+        let mut transfer = WriteTransfer::new(
+            "foo",
+            None,
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        assert!(matches!(
+            transmits(&mut transfer)[..],
+            [Packet::WriteRequest { .. }]
+        ));
+        assert!(!transfer.wants_data());
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![
+                TransferOption::new("blksize", "8").unwrap(),
+                TransferOption::new("windowsize", "2").unwrap(),
+            ],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        assert!(transfer.wants_data());
+        // Only a window's worth goes out, the rest waits
+        transfer.write(b"0123456789abcdefghij");
+        let data = |block_n: u16, data: &[u8]| Packet::Data {
+            block_n,
+            data: data.to_vec(),
+        };
+        assert_eq!(
+            transmits(&mut transfer),
+            [data(1, b"01234567"), data(2, b"89abcdef")]
+        );
+        assert!(!transfer.wants_data());
+        // The server only got the first block, so we roll back to the second
+        let ack = |block_n| Packet::Acknowledgment { block_n }.to_bytes();
+        transfer
+            .on_datagram(Instant::now(), tid(), &ack(1))
+            .unwrap();
+        assert_eq!(transmits(&mut transfer), [data(2, b"89abcdef")]);
+        // The rest is a short block, which only goes out once we're done
+        transfer
+            .on_datagram(Instant::now(), tid(), &ack(2))
+            .unwrap();
+        assert!(transmits(&mut transfer).is_empty());
+        transfer.finish();
+        assert_eq!(transmits(&mut transfer), [data(3, b"ghij")]);
+        transfer
+            .on_datagram(Instant::now(), tid(), &ack(3))
+            .unwrap();
+        assert!(transfer.is_done());
+        assert_eq!(transfer.written(), 20);
+    }
+
+    /// An observer that collects every event
+    fn recorder() -> (Arc<dyn Observer>, Arc<std::sync::Mutex<Vec<Event>>>) {
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let collected = events.clone();
+        let observer = move |event: &Event| collected.lock().unwrap().push(event.clone());
+        (Arc::new(observer), events)
+    }
+
+    #[test]
+    fn test_read_events() {
+        let options = Options {
+            blksize: Some(8),
+            tsize: true,
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        let (observer, events) = recorder();
+        transfer.observe(observer);
+        transmits(&mut transfer);
+        transfer.on_timeout(Instant::now()).unwrap();
+        transmits(&mut transfer);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![
+                TransferOption::new("blksize", "8").unwrap(),
+                TransferOption::new("tsize", "10").unwrap(),
+            ],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        for (block_n, data) in [(1, &b"01234567"[..]), (2, b"89")] {
+            let pkt = Packet::Data {
+                block_n,
+                data: data.to_vec(),
+            };
+            transfer
+                .on_datagram(Instant::now(), tid(), &pkt.to_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                Event::RequestSent,
+                Event::Timeout,
+                Event::Retransmit,
+                Event::Negotiated {
+                    blksize: 8,
+                    windowsize: 1,
+                    tsize: Some(10),
+                    timeout: None,
+                },
+                Event::Block {
+                    block: 1,
+                    bytes: 8,
+                    total: Some(10),
+                },
+                Event::Block {
+                    block: 2,
+                    bytes: 10,
+                    total: Some(10),
+                },
+                Event::Complete { bytes: 10 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_duplicate_oack() {
+        let options = Options {
+            blksize: Some(8),
+            ..Default::default()
+        };
+        let mut transfer = ReadTransfer::new(
+            "foo",
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        let (observer, events) = recorder();
+        transfer.observe(observer);
+        transmits(&mut transfer);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![TransferOption::new("blksize", "8").unwrap()],
+        };
+        for _ in 0..2 {
+            transfer
+                .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+                .unwrap();
+            assert_eq!(
+                transmits(&mut transfer),
+                [Packet::Acknowledgment { block_n: 0 }]
+            );
+        }
+        // Our ack went missing, so it's sent again without negotiating all over
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                Event::RequestSent,
+                Event::Negotiated {
+                    blksize: 8,
+                    windowsize: 1,
+                    tsize: None,
+                    timeout: None,
+                },
+                Event::Retransmit,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_events() {
+        let options = Options {
+            blksize: Some(8),
+            windowsize: Some(2),
+            ..Default::default()
+        };
+        let mut transfer = WriteTransfer::new(
+            "foo",
+            Some(12),
+            server(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            4,
+            &options,
+        )
+        .unwrap();
+        let (observer, events) = recorder();
+        transfer.observe(observer);
+        transmits(&mut transfer);
+        let oack = Packet::OptionAcknowledgment {
+            options: vec![
+                TransferOption::new("blksize", "8").unwrap(),
+                TransferOption::new("windowsize", "2").unwrap(),
+            ],
+        };
+        transfer
+            .on_datagram(Instant::now(), tid(), &oack.to_bytes())
+            .unwrap();
+        transfer.write(b"0123456789ab");
+        transfer.finish();
+        transmits(&mut transfer);
+        // Both blocks are acked at once, but each one counts
+        transfer
+            .on_datagram(
+                Instant::now(),
+                tid(),
+                &Packet::Acknowledgment { block_n: 2 }.to_bytes(),
+            )
+            .unwrap();
+        assert!(transfer.is_done());
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                Event::RequestSent,
+                Event::Negotiated {
+                    blksize: 8,
+                    windowsize: 2,
+                    tsize: None,
+                    timeout: None,
+                },
+                Event::Block {
+                    block: 1,
+                    bytes: 8,
+                    total: Some(12),
+                },
+                Event::Block {
+                    block: 2,
+                    bytes: 12,
+                    total: Some(12),
+                },
+                Event::Complete { bytes: 12 },
+            ]
+        );
+    }
+}
@@ -28,6 +28,1059 @@ fn download_upload() {
     assert_eq!(test_payload, res);
 }
 
+#[test]
+fn download_upload_unknown_option() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        parser::TransferOption,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    // Servers must ignore options they don't understand
+    let options = Options {
+        extra: vec![TransferOption::new("x-unknown", "1").unwrap()],
+        ..Default::default()
+    };
+    let test_payload = vec![0x0b, 0x0e, 0xa5, 0x7e];
+    upload_with_options(
+        "/test-options",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let res = download_with_options(
+        "/test-options",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn download_upload_blksize() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        blksize: Some(1428),
+        ..Default::default()
+    };
+    let test_payload: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    upload_with_options(
+        "/test-blksize",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let res = download_with_options(
+        "/test-blksize",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn download_upload_windowsize() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        blksize: Some(1024),
+        windowsize: Some(4),
+        ..Default::default()
+    };
+    let test_payload: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    upload_with_options(
+        "/test-windowsize",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let res = download_with_options(
+        "/test-windowsize",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn download_upload_rollover() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    // Small blocks so the block numbers wrap around without a huge file
+    let options = Options {
+        blksize: Some(8),
+        windowsize: Some(16),
+        ..Default::default()
+    };
+    let test_payload: Vec<u8> = (0..70000 * 8).map(|i| (i % 251) as u8).collect();
+    upload_with_options(
+        "/test-rollover",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let res = download_with_options(
+        "/test-rollover",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn download_upload_whole_blocks() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        dally: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    // Both of these end with an empty block
+    for test_payload in [vec![], vec![42; 1024]] {
+        upload_with_options(
+            "/test-whole-blocks",
+            &test_payload,
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &options,
+        )
+        .unwrap();
+        let res = download_with_options(
+            "/test-whole-blocks",
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(test_payload, res);
+    }
+}
+
+#[test]
+fn download_upload_tsize_timeout() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        query_size,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        tsize: true,
+        timeout: Some(1),
+        ..Default::default()
+    };
+    let test_payload: Vec<u8> = (0..1337).map(|i| i as u8).collect();
+    upload_with_options(
+        "/test-tsize",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let size = query_size(
+        "/test-tsize",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .unwrap();
+    assert_eq!(size, test_payload.len() as u64);
+    let res = download_with_options(
+        "/test-tsize",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn query_size_timeout() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        query_size,
+        Error,
+    };
+
+    // Nobody answers, and the socket's timeout is put back after giving up
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let read_timeout = Some(Duration::from_secs(7));
+    socket.set_read_timeout(read_timeout).unwrap();
+    let timeout = Duration::from_millis(50);
+    assert!(matches!(
+        query_size(
+            "/test",
+            &socket,
+            server.local_addr().unwrap(),
+            timeout,
+            timeout,
+            1
+        ),
+        Err(Error::Timeout)
+    ));
+    assert_eq!(socket.read_timeout().unwrap(), read_timeout);
+}
+
+#[test]
+fn download_upload_netascii() {
+    use std::net::UdpSocket;
+    use tftp_client::{
+        download_with_options,
+        parser::RequestMode,
+        upload_with_options,
+        Options,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        mode: RequestMode::NetAscii,
+        ..Default::default()
+    };
+    // Enough lines that some of the CR LF pairs straddle a block boundary
+    let test_payload: Vec<u8> = (0..200)
+        .flat_map(|i| format!("line {i}\rcol\n").into_bytes())
+        .collect();
+    upload_with_options(
+        "/test-netascii",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let res = download_with_options(
+        "/test-netascii",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn download_to_writer() {
+    use std::{
+        io::Write,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        download_to,
+        upload,
+        Error,
+        Options,
+    };
+
+    struct FullWriter;
+
+    impl Write for FullWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let test_payload: Vec<u8> = (0..3000).map(|i| (i % 13) as u8).collect();
+    upload(
+        "/test-writer",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .unwrap();
+    let mut res = vec![];
+    let n = download_to(
+        "/test-writer",
+        &mut res,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    assert_eq!(test_payload, res);
+    let err = download_to(
+        "/test-writer",
+        FullWriter,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, Error::Write(_)));
+}
+
+#[test]
+fn upload_from_reader() {
+    use std::{
+        io::Read,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        download,
+        upload_from,
+        Options,
+    };
+
+    // Hands out a few bytes at a time, like a pipe would
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let test_payload: Vec<u8> = (0..2048).map(|i| (i % 17) as u8).collect();
+    let n = upload_from(
+        "/test-reader",
+        Trickle(&test_payload),
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    let res = download(
+        "/test-reader",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn tftp_reader() {
+    use std::{
+        io::Read,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        upload,
+        Options,
+        TftpReader,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let test_payload: Vec<u8> = (0..3000).map(|i| (i % 13) as u8).collect();
+    upload(
+        "/test-tftp-reader",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .unwrap();
+    let options = Options {
+        tsize: true,
+        ..Default::default()
+    };
+    let mut reader = TftpReader::new(
+        "/test-tftp-reader",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    // Read in chunks that don't line up with the blocks
+    let mut res = vec![];
+    let mut buf = [0; 100];
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        res.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(reader.transfer_size(), Some(test_payload.len() as u64));
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn tftp_reader_failed() {
+    use std::{
+        io::{
+            ErrorKind,
+            Read,
+        },
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        parser::Packet,
+        Options,
+        TftpReader,
+    };
+
+    // A server that sends the first block and then goes quiet
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let data = Packet::Data {
+            block_n: 1,
+            data: vec![0; 512],
+        };
+        server.send_to(&data.to_bytes(), client).unwrap();
+        server
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let timeout = Duration::from_millis(50);
+    let mut reader = TftpReader::new(
+        "/test",
+        &socket,
+        addr,
+        timeout,
+        timeout,
+        1,
+        &Options::default(),
+    )
+    .unwrap();
+    let mut buf = [0; 512];
+    assert_eq!(reader.read(&mut buf).unwrap(), 512);
+    // The timeout sticks, rather than the file looking like it ended early
+    for _ in 0..2 {
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+    }
+    handle.join().unwrap();
+}
+
+#[test]
+fn tftp_reader_dropped() {
+    use std::{
+        io::Read,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        parser::Packet,
+        Options,
+        TftpReader,
+    };
+
+    // A server that sends the first block, collecting whatever it gets back
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let data = Packet::Data {
+            block_n: 1,
+            data: vec![0; 512],
+        };
+        server.send_to(&data.to_bytes(), client).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut received = vec![];
+        while let Ok((n, _)) = server.recv_from(&mut buf) {
+            received.push(Packet::from_bytes(&buf[..n]).unwrap());
+        }
+        received
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let timeout = Duration::from_millis(100);
+    let mut reader = TftpReader::new(
+        "/test",
+        &socket,
+        addr,
+        timeout,
+        timeout,
+        1,
+        &Options::default(),
+    )
+    .unwrap();
+    let mut buf = [0; 100];
+    assert_eq!(reader.read(&mut buf).unwrap(), 100);
+    // Giving up partway through tells the server, and leaves the socket as it was
+    drop(reader);
+    assert_eq!(socket.read_timeout().unwrap(), None);
+    let received = handle.join().unwrap();
+    assert!(matches!(
+        received[..],
+        [Packet::Acknowledgment { block_n: 1 }, Packet::Error { .. }]
+    ));
+}
+
+#[test]
+fn tftp_writer() {
+    use std::{
+        io::Write,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        download,
+        Options,
+        TftpWriter,
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        windowsize: Some(4),
+        ..Default::default()
+    };
+    let mut writer = TftpWriter::new(
+        "/test-tftp-writer",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .unwrap();
+    let mut test_payload = vec![];
+    for i in 0..200 {
+        writeln!(writer, "Line {i} of the report").unwrap();
+        writeln!(test_payload, "Line {i} of the report").unwrap();
+    }
+    // Pad out to a whole number of blocks, which has to end with an empty one
+    let padding = vec![b'.'; 512 - test_payload.len() % 512];
+    writer.write_all(&padding).unwrap();
+    test_payload.extend_from_slice(&padding);
+    let n = writer.finish().unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    let res = download(
+        "/test-tftp-writer",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+fn tftp_writer_failed() {
+    use std::{
+        io::{
+            ErrorKind,
+            Write,
+        },
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        parser::Packet,
+        Error,
+        Options,
+        TftpWriter,
+    };
+
+    // A server that acks the request and then stops listening, collecting whatever else it gets
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let ack = Packet::Acknowledgment { block_n: 0 };
+        server.send_to(&ack.to_bytes(), client).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut received = vec![];
+        while let Ok((n, _)) = server.recv_from(&mut buf) {
+            received.push(Packet::from_bytes(&buf[..n]).unwrap());
+        }
+        received
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let timeout = Duration::from_millis(50);
+    let mut writer = TftpWriter::new(
+        "/test",
+        &socket,
+        addr,
+        timeout,
+        timeout,
+        1,
+        &Options::default(),
+    )
+    .unwrap();
+    let err = writer.write_all(&[0; 600]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    // The timeout sticks, and nothing more goes out after we gave up
+    assert_eq!(
+        writer.write(&[0; 600]).unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+    assert!(matches!(writer.finish(), Err(Error::Timeout)));
+    let received = handle.join().unwrap();
+    assert!(matches!(received.last(), Some(Packet::Error { .. })));
+}
+
+#[test]
+fn tftp_writer_dropped() {
+    use std::{
+        io::Write,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        parser::Packet,
+        Options,
+        TftpWriter,
+    };
+
+    // A server that acks the request, collecting whatever else it gets
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let ack = Packet::Acknowledgment { block_n: 0 };
+        server.send_to(&ack.to_bytes(), client).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut received = vec![];
+        while let Ok((n, _)) = server.recv_from(&mut buf) {
+            received.push(Packet::from_bytes(&buf[..n]).unwrap());
+        }
+        received
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let timeout = Duration::from_millis(100);
+    let mut writer = TftpWriter::new(
+        "/test",
+        &socket,
+        addr,
+        timeout,
+        timeout,
+        1,
+        &Options::default(),
+    )
+    .unwrap();
+    writer.write_all(&[0; 100]).unwrap();
+    // Dropping the writer instead of finishing tells the server, rather than sending the short
+    // block that would look like the end of the file
+    drop(writer);
+    assert_eq!(socket.read_timeout().unwrap(), None);
+    let received = handle.join().unwrap();
+    assert!(matches!(received[..], [Packet::Error { .. }]));
+}
+
+#[test]
+fn client() {
+    use std::io::{
+        Read,
+        Write,
+    };
+    use tftp_client::Client;
+
+    let client = Client::builder("127.0.0.1:69".parse().unwrap())
+        .timeout(Duration::from_millis(100))
+        .max_timeout(Duration::from_secs(5))
+        .blksize(1024)
+        .build()
+        .unwrap();
+    let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    client.put("/test-client", &test_payload).unwrap();
+    assert_eq!(client.get("/test-client").unwrap(), test_payload);
+    let mut writer = client.writer("/test-client-writer").unwrap();
+    writer.write_all(&test_payload).unwrap();
+    writer.finish().unwrap();
+    let mut res = vec![];
+    client
+        .reader("/test-client-writer")
+        .unwrap()
+        .read_to_end(&mut res)
+        .unwrap();
+    assert_eq!(res, test_payload);
+}
+
+#[test]
+fn client_retry_policy() {
+    use std::time::Instant;
+    use tftp_client::{
+        retry::{
+            Constant,
+            Exponential,
+            Jacobson,
+        },
+        Client,
+        Error,
+        Options,
+    };
+
+    let policy =
+        Exponential::new(Duration::from_millis(100), Duration::from_secs(5), 8).with_jitter(0.25);
+    let client = Client::builder("127.0.0.1:69".parse().unwrap())
+        .retry_policy(policy)
+        .build()
+        .unwrap();
+    let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    client.put("/test-client-policy", &test_payload).unwrap();
+    assert_eq!(client.get("/test-client-policy").unwrap(), test_payload);
+
+    let policy = Jacobson::new(Duration::from_millis(100), Duration::from_secs(5), 8);
+    let client = Client::builder("127.0.0.1:69".parse().unwrap())
+        .retry_policy(policy)
+        .build()
+        .unwrap();
+    client.put("/test-client-jacobson", &test_payload).unwrap();
+    assert_eq!(client.get("/test-client-jacobson").unwrap(), test_payload);
+
+    // Nothing is listening here, so we'd keep retrying for a long time if not for the deadline
+    let client = Client::builder("127.0.0.1:9".parse().unwrap())
+        .retry_policy(Constant::new(Duration::from_millis(100), 1000))
+        .options(Options {
+            deadline: Some(Duration::from_millis(500)),
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+    let start = Instant::now();
+    assert!(matches!(client.get("/test"), Err(Error::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    // Not waiting at all is a timeout too, not an error from the socket
+    let client = Client::builder("127.0.0.1:9".parse().unwrap())
+        .retry_policy(Constant::new(Duration::ZERO, 4))
+        .build()
+        .unwrap();
+    assert!(matches!(client.get("/test"), Err(Error::Timeout)));
+}
+
+#[test]
+fn transfer_retries() {
+    use std::{
+        ffi::CString,
+        net::UdpSocket,
+    };
+    use tftp_client::{
+        parser::{
+            ErrorCode,
+            Packet,
+        },
+        Client,
+        Error,
+        Options,
+    };
+
+    // A server that forgets about every other request
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        for forget in [true, false, true] {
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            let reply = if forget {
+                Packet::Error {
+                    code: ErrorCode::BadId,
+                    msg: CString::new("Unknown transfer ID").unwrap(),
+                }
+            } else {
+                Packet::Data {
+                    block_n: 1,
+                    data: b"hello".to_vec(),
+                }
+            };
+            server.send_to(&reply.to_bytes(), client).unwrap();
+            if !forget {
+                let (n, _) = server.recv_from(&mut buf).unwrap();
+                assert_eq!(
+                    Packet::from_bytes(&buf[..n]).unwrap(),
+                    Packet::Acknowledgment { block_n: 1 }
+                );
+            }
+        }
+    });
+    let client = |transfer_retries| {
+        Client::builder(addr)
+            .timeout(Duration::from_millis(100))
+            .options(Options {
+                transfer_retries,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    };
+    // The whole transfer starts over after the server forgets about it
+    assert_eq!(client(1).get("/test").unwrap(), b"hello");
+    // Unless we don't want it to
+    assert!(matches!(
+        client(0).get("/test"),
+        Err(Error::Protocol {
+            code: ErrorCode::BadId,
+            ..
+        })
+    ));
+    handle.join().unwrap();
+}
+
+#[test]
+fn transfer_retries_deadline() {
+    use std::{
+        ffi::CString,
+        net::UdpSocket,
+        time::Instant,
+    };
+    use tftp_client::{
+        parser::{
+            ErrorCode,
+            Packet,
+        },
+        Client,
+        Options,
+    };
+
+    // A server that takes its time to forget about every request
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut buf = [0; 1024];
+        while let Ok((_, client)) = server.recv_from(&mut buf) {
+            std::thread::sleep(Duration::from_millis(50));
+            let reply = Packet::Error {
+                code: ErrorCode::BadId,
+                msg: CString::new("Unknown transfer ID").unwrap(),
+            };
+            server.send_to(&reply.to_bytes(), client).unwrap();
+        }
+    });
+    let client = Client::builder(addr)
+        .timeout(Duration::from_millis(100))
+        .options(Options {
+            deadline: Some(Duration::from_millis(300)),
+            transfer_retries: 100,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+    // The deadline is for the whole transfer, not every time it starts over
+    let start = Instant::now();
+    assert!(client.get("/test").is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    handle.join().unwrap();
+}
+
+#[test]
+fn client_observer() {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+    use tftp_client::{
+        transfer::Event,
+        Client,
+        Options,
+    };
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let collected = events.clone();
+    let client = Client::builder("127.0.0.1:69".parse().unwrap())
+        .timeout(Duration::from_millis(100))
+        .options(Options {
+            blksize: Some(1024),
+            tsize: true,
+            ..Default::default()
+        })
+        .observer(move |event: &Event| collected.lock().unwrap().push(event.clone()))
+        .build()
+        .unwrap();
+    let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    for transfer in [
+        |client: &Client, payload: &[u8]| client.put("/test-client-observer", payload).unwrap(),
+        |client: &Client, payload: &[u8]| {
+            assert_eq!(client.get("/test-client-observer").unwrap(), payload)
+        },
+    ] {
+        events.lock().unwrap().clear();
+        transfer(&client, &test_payload);
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], Event::RequestSent);
+        assert!(matches!(
+            events[1],
+            Event::Negotiated {
+                blksize: 1024,
+                tsize: Some(5000),
+                ..
+            }
+        ));
+        let blocks: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Block { bytes, total, .. } => Some((*bytes, *total)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(blocks[4], (5000, Some(5000)));
+        assert_eq!(events.last(), Some(&Event::Complete { bytes: 5000 }));
+    }
+}
+
 #[test]
 #[cfg(feature = "async")]
 fn download_upload_async() {
@@ -68,3 +1121,511 @@ fn download_upload_async() {
         assert_eq!(test_payload, res);
     });
 }
+
+#[test]
+#[cfg(feature = "async")]
+fn download_upload_blksize_async() {
+    use async_net::UdpSocket;
+    use tftp_client::{
+        asynchronous::{
+            download_with_options,
+            upload_with_options,
+        },
+        Options,
+    };
+
+    futures_lite::future::block_on(async move {
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let server = "127.0.0.1:69".parse().unwrap();
+        let timeout = Duration::from_millis(100);
+        let max_timeout = Duration::from_secs(5);
+        let retries = 8;
+        let options = Options {
+            blksize: Some(1428),
+            ..Default::default()
+        };
+        let test_payload: Vec<u8> = (0..5000).map(|i| (i * 7) as u8).collect();
+        upload_with_options(
+            "/test-blksize-async",
+            &test_payload,
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &options,
+        )
+        .await
+        .unwrap();
+        let res = download_with_options(
+            "/test-blksize-async",
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(test_payload, res);
+    });
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn upload_from_download_to_async() {
+    use async_net::UdpSocket;
+    use tftp_client::{
+        asynchronous::{
+            download_to,
+            upload_from,
+        },
+        Options,
+    };
+
+    futures_lite::future::block_on(async move {
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let server = "127.0.0.1:69".parse().unwrap();
+        let timeout = Duration::from_millis(100);
+        let max_timeout = Duration::from_secs(5);
+        let retries = 8;
+        let test_payload: Vec<u8> = (0..1536).map(|i| (i % 19) as u8).collect();
+        let n = upload_from(
+            "/test-stream-async",
+            &test_payload[..],
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &Options::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(n, test_payload.len() as u64);
+        let mut res = vec![];
+        let n = download_to(
+            "/test-stream-async",
+            &mut res,
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+            &Options::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(n, test_payload.len() as u64);
+        assert_eq!(test_payload, res);
+    });
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn download_upload_custom_transport() {
+    use std::{
+        cell::Cell,
+        future::Future,
+        net::SocketAddr,
+    };
+    use tftp_client::asynchronous::{
+        download,
+        upload,
+        Transport,
+    };
+
+    // Drops every third datagram we send, which the client has to recover from
+    struct Lossy {
+        socket: async_net::UdpSocket,
+        sent: Cell<usize>,
+    }
+
+    impl Transport for Lossy {
+        async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+            self.sent.set(self.sent.get() + 1);
+            if self.sent.get() % 3 == 0 {
+                return Ok(buf.len());
+            }
+            self.socket.send_to(buf, target).await
+        }
+
+        fn recv_from(
+            &self,
+            buf: &mut [u8],
+        ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>> {
+            self.socket.recv_from(buf)
+        }
+
+        fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+            self.socket.sleep(duration)
+        }
+    }
+
+    futures_lite::future::block_on(async move {
+        let socket = Lossy {
+            socket: async_net::UdpSocket::bind("0.0.0.0:0").await.unwrap(),
+            sent: Cell::new(0),
+        };
+        let server = "127.0.0.1:69".parse().unwrap();
+        let timeout = Duration::from_millis(100);
+        let max_timeout = Duration::from_secs(5);
+        let retries = 8;
+        let test_payload: Vec<u8> = (0..3000).map(|i| (i % 29) as u8).collect();
+        upload(
+            "/test-custom-transport",
+            &test_payload,
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+        )
+        .await
+        .unwrap();
+        let res = download(
+            "/test-custom-transport",
+            &socket,
+            server,
+            timeout,
+            max_timeout,
+            retries,
+        )
+        .await
+        .unwrap();
+        assert_eq!(test_payload, res);
+    });
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn client_async() {
+    use tftp_client::ClientBuilder;
+
+    futures_lite::future::block_on(async {
+        let client = ClientBuilder::new("127.0.0.1:69".parse().unwrap())
+            .timeout(Duration::from_millis(100))
+            .max_timeout(Duration::from_secs(5))
+            .blksize(1024)
+            .build_async()
+            .await
+            .unwrap();
+        let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        client
+            .put("/test-client-async", &test_payload)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("/test-client-async").await.unwrap(),
+            test_payload
+        );
+        let n = client
+            .put_from("/test-client-async-stream", &test_payload[..])
+            .await
+            .unwrap();
+        assert_eq!(n, test_payload.len() as u64);
+        let mut res = vec![];
+        client
+            .get_to("/test-client-async-stream", &mut res)
+            .await
+            .unwrap();
+        assert_eq!(res, test_payload);
+    });
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn client_observer_async() {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+    use tftp_client::{
+        transfer::Event,
+        ClientBuilder,
+        Options,
+    };
+
+    futures_lite::future::block_on(async {
+        let events = Arc::new(Mutex::new(vec![]));
+        let collected = events.clone();
+        let client = ClientBuilder::new("127.0.0.1:69".parse().unwrap())
+            .timeout(Duration::from_millis(100))
+            .options(Options {
+                blksize: Some(1024),
+                tsize: true,
+                ..Default::default()
+            })
+            .observer(move |event: &Event| collected.lock().unwrap().push(event.clone()))
+            .build_async()
+            .await
+            .unwrap();
+        let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        client
+            .put("/test-client-observer-async", &test_payload)
+            .await
+            .unwrap();
+        events.lock().unwrap().clear();
+        assert_eq!(
+            client.get("/test-client-observer-async").await.unwrap(),
+            test_payload
+        );
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], Event::RequestSent);
+        assert!(events.contains(&Event::Block {
+            block: 5,
+            bytes: 5000,
+            total: Some(5000),
+        }));
+        assert_eq!(events.last(), Some(&Event::Complete { bytes: 5000 }));
+    });
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn client_tokio() {
+    use tftp_client::ClientBuilder;
+
+    let client = ClientBuilder::new("127.0.0.1:69".parse().unwrap())
+        .timeout(Duration::from_millis(100))
+        .max_timeout(Duration::from_secs(5))
+        .build_tokio()
+        .await
+        .unwrap();
+    let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    client
+        .put("/test-client-tokio", &test_payload)
+        .await
+        .unwrap();
+    // Transfers can run on other tasks
+    let client = std::sync::Arc::new(client);
+    let get = tokio::spawn({
+        let client = client.clone();
+        async move { client.get("/test-client-tokio").await }
+    });
+    assert_eq!(get.await.unwrap().unwrap(), test_payload);
+    // Streaming goes through tokio's own `AsyncRead` and `AsyncWrite`
+    let n = client
+        .put_from_tokio("/test-client-tokio-stream", &test_payload[..])
+        .await
+        .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    let mut res = vec![];
+    let n = client
+        .get_to_tokio("/test-client-tokio-stream", &mut res)
+        .await
+        .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    assert_eq!(res, test_payload);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn download_upload_tokio() {
+    use tftp_client::tokio::{
+        download,
+        upload,
+    };
+    use tokio::net::UdpSocket;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let test_payload = vec![0x70, 0x4b, 0x10, 0x00];
+    upload(
+        "/test-tokio",
+        &test_payload,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .await
+    .unwrap();
+    let res = download(
+        "/test-tokio",
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+    )
+    .await
+    .unwrap();
+    assert_eq!(test_payload, res);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn upload_from_download_to_tokio() {
+    use tftp_client::{
+        tokio::{
+            download_to,
+            upload_from,
+        },
+        Options,
+    };
+    use tokio::net::UdpSocket;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    let server = "127.0.0.1:69".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let max_timeout = Duration::from_secs(5);
+    let retries = 8;
+    let options = Options {
+        blksize: Some(1024),
+        windowsize: Some(4),
+        ..Default::default()
+    };
+    let test_payload: Vec<u8> = (0..9000).map(|i| (i % 23) as u8).collect();
+    let n = upload_from(
+        "/test-stream-tokio",
+        &test_payload[..],
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    let mut res = vec![];
+    let n = download_to(
+        "/test-stream-tokio",
+        &mut res,
+        &socket,
+        server,
+        timeout,
+        max_timeout,
+        retries,
+        &options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    assert_eq!(test_payload, res);
+}
+
+#[test]
+#[cfg(feature = "embedded")]
+fn download_upload_embedded() {
+    use std::{
+        io::ErrorKind,
+        net::{
+            SocketAddr,
+            UdpSocket,
+        },
+        time::Instant,
+    };
+
+    use embedded_nal::{
+        nb,
+        UdpClientStack,
+    };
+    use tftp_client::embedded::{
+        download,
+        upload,
+        Clock,
+        Config,
+    };
+
+    /// A network stack backed by std sockets, which hands over datagrams from any source
+    struct StdStack;
+
+    impl UdpClientStack for StdStack {
+        type UdpSocket = (UdpSocket, Option<SocketAddr>);
+        type Error = std::io::Error;
+
+        fn socket(&mut self) -> Result<Self::UdpSocket, Self::Error> {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.set_nonblocking(true)?;
+            Ok((socket, None))
+        }
+
+        fn connect(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            remote: SocketAddr,
+        ) -> Result<(), Self::Error> {
+            socket.1 = Some(remote);
+            Ok(())
+        }
+
+        fn send(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            buffer: &[u8],
+        ) -> nb::Result<(), Self::Error> {
+            socket.0.send_to(buffer, socket.1.unwrap())?;
+            Ok(())
+        }
+
+        fn receive(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            buffer: &mut [u8],
+        ) -> nb::Result<(usize, SocketAddr), Self::Error> {
+            socket.0.recv_from(buffer).map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock => nb::Error::WouldBlock,
+                _ => nb::Error::Other(e),
+            })
+        }
+
+        fn close(&mut self, _socket: Self::UdpSocket) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    struct StdClock(Instant);
+
+    impl Clock for StdClock {
+        fn now(&mut self) -> Duration {
+            self.0.elapsed()
+        }
+    }
+
+    let mut stack = StdStack;
+    let mut clock = StdClock(Instant::now());
+    let server = "127.0.0.1:69".parse().unwrap();
+    let config = Config {
+        timeout: Duration::from_millis(100),
+        blksize: Some(1024),
+        ..Default::default()
+    };
+    let mut buf = [0; 1028];
+    let test_payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    let mut remaining = &test_payload[..];
+    let n = upload(
+        &mut stack,
+        &mut clock,
+        server,
+        "/test-embedded",
+        &mut buf,
+        &config,
+        |block: &mut [u8]| std::io::Read::read(&mut remaining, block),
+    )
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    let mut res = vec![];
+    let n = download(
+        &mut stack,
+        &mut clock,
+        server,
+        "/test-embedded",
+        &mut buf,
+        &config,
+        |data: &[u8]| -> Result<(), ()> {
+            res.extend_from_slice(data);
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(n, test_payload.len() as u64);
+    assert_eq!(test_payload, res);
+}